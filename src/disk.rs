@@ -1,14 +1,57 @@
-use std::collections::VecDeque;
-use std::io::prelude::*;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::SeekFrom;
-use std::mem;
-
-use page::{Page, PAGE_SIZE, HEADER_SIZE};
+use std::fs::{self, OpenOptions};
+use std::sync::Arc;
+
+use error::{Error, Result};
+use hash::{KeyHasher, XxHash64};
+use options::LinHashOptions;
+use page::{Page, DEFAULT_PAGE_SIZE, HEADER_SIZE};
+use pool::{BufferPool, Journal, PageGuard, PoolStats};
 use util::*;
-
-const NUM_BUFFERS : usize = 16;
+use wal::Wal;
+
+// Every table file starts with these bytes
+const MAGIC : [u8; 8] = *b"LINHASH\0";
+// Bumped whenever the layout of the file changes
+const FORMAT_VERSION : usize = 5;
+
+// Control page fields
+const CTRL_VERSION : usize = 1;
+const CTRL_PAGE_SIZE : usize = 2;
+const CTRL_KEYSIZE : usize = 3;
+const CTRL_VALSIZE : usize = 4;
+const CTRL_HASHER : usize = 5;
+const CTRL_NBITS : usize = 6;
+const CTRL_NITEMS : usize = 7;
+const CTRL_NBUCKETS : usize = 8;
+const CTRL_NUM_PAGES : usize = 9;
+const CTRL_FREE_LIST : usize = 10;
+const CTRL_NUM_FREE : usize = 11;
+const CTRL_NBYTES : usize = 12;
+const CTRL_DIR_ROOT : usize = 13;
+const CTRL_HASH_KEY : usize = 14; // two fields
+const CTRL_SHADOW_PAGING : usize = 16;
+const CTRL_MAP_ROOT : usize = 17;
+const CTRL_GENERATION : usize = 18;
+const CTRL_CHECKSUM : usize = 19;
+
+// The control page holds two slots of this many bytes, which are
+// written in turn, so that a torn write leaves the other one intact.
+// Fits in the smallest page.
+const CTRL_SLOT_SIZE : usize = 256;
+
+/// Parameters a table is created with, stored in its control page.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub page_size: usize,
+    pub keysize: usize,
+    pub valsize: usize,
+    // which function keys are hashed with, and the secret key it is
+    // seeded with
+    pub hasher: usize,
+    pub hash_key: [u64; 2],
+    // are pages written with shadow paging, see `Shadow`
+    pub shadow_paging: bool,
+}
 
 pub struct SearchResult {
     pub page_id: Option<usize>,
@@ -24,205 +67,483 @@ fn flatten<T>(v: Vec<(usize, Vec<T>)>) -> Vec<T> {
     result
 }
 
+type BucketRecords = Vec<(usize, Vec<(Vec<u8>, Vec<u8>)>)>;
+
+fn read_dir_entry(page: &Page, i: usize) -> usize {
+    let offset = HEADER_SIZE + i * 8;
+    bytearray_to_usize(page.storage[offset..offset+8].to_vec())
+}
+
+fn slot_field(slot: &[u8], field: usize) -> usize {
+    bytearray_to_usize(slot[field*8..(field+1)*8].to_vec())
+}
+
+fn set_slot_field(slot: &mut [u8], field: usize, value: usize) {
+    mem_move(&mut slot[field*8..(field+1)*8], &usize_to_bytearray(value));
+}
+
+fn slot_checksum(slot: &[u8]) -> usize {
+    XxHash64.hash(&slot[..CTRL_CHECKSUM*8]) as usize
+}
+
+// Does `slot` hold a control page that was written completely?
+fn valid_slot(slot: &[u8]) -> bool {
+    slot[0..8] == MAGIC[..] && slot_field(slot, CTRL_CHECKSUM) == slot_checksum(slot)
+}
+
+// Stores `map_root` in `slot`, and then its checksum.
+fn seal_slot(slot: &mut [u8], map_root: usize) {
+    set_slot_field(slot, CTRL_MAP_ROOT, map_root);
+    let checksum = slot_checksum(slot);
+    set_slot_field(slot, CTRL_CHECKSUM, checksum);
+}
+
+fn slot_range(slot: usize) -> ::std::ops::Range<usize> {
+    slot * CTRL_SLOT_SIZE..(slot + 1) * CTRL_SLOT_SIZE
+}
+
+fn write_dir_entry(page: &mut Page, i: usize, page_id: usize) {
+    let offset = HEADER_SIZE + i * 8;
+    mem_move(&mut page.storage[offset..offset+8], &usize_to_bytearray(page_id));
+}
+
 pub struct DbFile {
-    path: String,
-    file: File,
+    header: Header,
+    page_size: usize,
     ctrl_buffer: Page,
-    pub buffers: VecDeque<Page>,
-    pub records_per_page: usize,
+    // slot of `ctrl_buffer` holding the newest control page, and its
+    // generation
+    ctrl_slot: usize,
+    generation: usize,
+    pool: Arc<BufferPool>,
+    // id the file is registered with in `pool`
+    file_id: usize,
+    // is `pool` used by other tables too?
+    shared_pool: bool,
     bucket_to_page: Vec<usize>,
-    keysize: usize,
-    valsize: usize,
+    // pages holding `bucket_to_page` on disk, in chain order
+    dir_pages: Vec<usize>,
+    // number of `bucket_to_page` mappings written to `dir_pages`
+    dir_synced: usize,
     num_pages: usize,
-    // overflow pages no longer in use
+    // pages no longer in use
     free_list: Option<usize>,
     num_free: usize,
 }
 
+fn page_records(page: &Page) -> Vec<(Vec<u8>, Vec<u8>)> {
+    (0..page.num_records).map(|i| {
+        let (k, v) = page.read_record(i);
+        (k.to_vec(), v.to_vec())
+    }).collect()
+}
+
+fn wal_path(filename: &str) -> String {
+    format!("{}-wal", filename)
+}
+
 impl DbFile {
-    pub fn new(filename: &str, keysize: usize, valsize: usize) -> DbFile {
+    /// Opens `filename`, creating or truncating it as set in
+    /// `options`, and registers it with `options.buffer_pool` or a
+    /// pool of its own. With its own pool, pages are
+    /// `options.page_size` bytes until the header of an existing
+    /// table is read.
+    ///
+    /// If a write-ahead log was left next to the file, what it holds
+    /// up to its last commit is copied into the file first. Changed
+    /// pages are written in place until `use_wal` is called.
+    pub fn new(filename: &str, options: &LinHashOptions) -> Result<DbFile> {
         let file = OpenOptions::new()
             .read(true)
             .write(true)
-            .create(true)
-            .open(filename);
-        let file = match file {
-            Ok(f) => f,
-            Err(e) => panic!(e),
-        };
+            .create(options.create)
+            .truncate(options.truncate)
+            .open(filename)?;
 
-        let total_size = keysize + valsize;
-        let records_per_page = (PAGE_SIZE - HEADER_SIZE) / total_size;
-
-        let mut buffers : VecDeque<Page> =
-            VecDeque::with_capacity(NUM_BUFFERS);
-        for _i in 0..NUM_BUFFERS {
-            buffers.push_back(Page::new(keysize, valsize));
+        let wal_path = wal_path(filename);
+        if options.truncate && fs::metadata(&wal_path).is_ok() {
+            fs::remove_file(&wal_path)?;
         }
+        Wal::recover(&wal_path, &file)?;
 
-        DbFile {
-            path: String::from(filename),
-            file: file,
-            ctrl_buffer: Page::new(0, 0),
-            buffers: buffers,
-            records_per_page: records_per_page,
+        let (pool, shared_pool) = match options.buffer_pool {
+            Some(ref pool) => (pool.clone(), true),
+            None => {
+                let page_size = options.page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+                let pool = BufferPool::new(page_size, options.cache_capacity,
+                                           options.replacement)?;
+                (Arc::new(pool), false)
+            },
+        };
+        let page_size = pool.page_size();
+        let file_id = pool.register(file, Journal::InPlace);
+        pool.set_verify_checksums(file_id, options.verify_checksums);
+        Ok(DbFile {
+            header: Header {
+                page_size,
+                keysize: 0,
+                valsize: 0,
+                hasher: 0,
+                hash_key: [0, 0],
+                shadow_paging: false,
+            },
+            page_size,
+            ctrl_buffer: Page::new(page_size),
+            ctrl_slot: 1,
+            generation: 0,
+            pool,
+            file_id,
+            shared_pool,
             bucket_to_page: vec![1, 2],
-            keysize: keysize,
-            valsize: valsize,
+            dir_pages: vec![],
+            dir_synced: 0,
             num_pages: 3,
             free_list: Some(3),
             num_free: 0,
-        }
+        })
     }
 
-    // Control page layout:
+    // Control page slot layout, each field 8 bytes long:
+    //
+    // | magic | version | page_size | keysize | valsize | hasher |
+    // nbits | nitems | nbuckets | num_pages | free_list root |
+    // num_free | nbytes | directory root | hash key (16 bytes) |
+    // shadow paging | page map root | generation | checksum |
+    //
+    // Each write of the control page goes to the slot not holding the
+    // newest one, with the next generation, and only that slot is
+    // written to the file. The checksum covers the fields before it.
     //
-    // | nbits | nitems | nbuckets | num_pages | free_list root |
-    // num_free | bucket_to_page mappings .... |
-    pub fn read_ctrlpage(&mut self) -> (usize, usize, usize) {
-        self.get_ctrl_page();
-        let nbits : usize = bytearray_to_usize(self.ctrl_buffer.storage[0..8].to_vec());
-        let nitems : usize =
-            bytearray_to_usize(self.ctrl_buffer.storage[8..16].to_vec());
-        let nbuckets : usize =
-            bytearray_to_usize(self.ctrl_buffer.storage[16..24].to_vec());
-
-        self.num_pages =
-            bytearray_to_usize(self.ctrl_buffer.storage[24..32].to_vec());
-        let free_list_head = bytearray_to_usize(self.ctrl_buffer.storage[32..40].to_vec());
+    // The bucket_to_page mappings are kept in a chain of directory
+    // pages starting at directory root. Each directory page holds
+    // `num_records` mappings after its header.
+    fn ctrl_field(&self, field: usize) -> usize {
+        slot_field(&self.ctrl_buffer.storage[slot_range(self.ctrl_slot)], field)
+    }
+
+    // Sets `field` in the slot the next control page is written to.
+    fn set_ctrl_field(&mut self, field: usize, value: usize) {
+        let next_slot = 1 - self.ctrl_slot;
+        set_slot_field(&mut self.ctrl_buffer.storage[slot_range(next_slot)], field, value);
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Use pages of `page_size` bytes. A shared buffer pool has a
+    /// fixed page size, so tables with other page sizes cannot use it.
+    fn set_page_size(&mut self, page_size: usize) -> Result<()> {
+        if page_size == self.page_size {
+            return Ok(());
+        }
+        if self.shared_pool {
+            return Err(Error::Mismatch {
+                param: "page size",
+                requested: self.page_size,
+                stored: page_size,
+            });
+        }
+        self.page_size = page_size;
+        self.ctrl_buffer = Page::new(page_size);
+        self.pool.set_page_size(page_size);
+        Ok(())
+    }
+
+    pub fn pool_stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
+
+    pub fn cache_capacity(&self) -> usize {
+        self.pool.capacity()
+    }
+
+    /// See `BufferPool::set_capacity`.
+    pub fn set_cache_capacity(&mut self, capacity: usize) -> Result<()> {
+        self.pool.set_capacity(capacity)
+    }
+
+    /// Pins page `page_id` for reading. See `BufferPool::pin`.
+    pub fn pin(&self, page_id: usize) -> Result<PageGuard<'_>> {
+        self.pool.pin(self.file_id, page_id)
+    }
+
+    // bucket_to_page mappings held by one directory page
+    fn dir_entries_per_page(&self) -> usize {
+        (self.page_size - HEADER_SIZE) / 8
+    }
+
+    /// See `BufferPool::log_full`.
+    pub fn log_full(&self) -> bool {
+        self.pool.log_full(self.file_id)
+    }
+
+    /// Has nothing been written to the file yet?
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.pool.file_len(self.file_id)? == 0)
+    }
+
+    /// Reads and validates the parameters the table was created with.
+    pub fn read_header(&mut self) -> Result<Header> {
+        self.get_ctrl_page()?;
+        let page_size = self.ctrl_field(CTRL_PAGE_SIZE);
+        if !Page::valid_size(page_size) {
+            return Err(Error::Corrupt { page_id: 0, reason: "invalid page size" });
+        }
+
+        self.header = Header {
+            page_size,
+            keysize: self.ctrl_field(CTRL_KEYSIZE),
+            valsize: self.ctrl_field(CTRL_VALSIZE),
+            hasher: self.ctrl_field(CTRL_HASHER),
+            hash_key: [self.ctrl_field(CTRL_HASH_KEY) as u64,
+                       self.ctrl_field(CTRL_HASH_KEY + 1) as u64],
+            shadow_paging: self.ctrl_field(CTRL_SHADOW_PAGING) != 0,
+        };
+        // Before a new page size clears `ctrl_buffer`
+        let map_root = self.ctrl_field(CTRL_MAP_ROOT);
+        self.set_page_size(page_size)?;
+        self.set_journal(map_root)?;
+        Ok(self.header.clone())
+    }
+
+    /// Set the parameters of a newly created table. They are written
+    /// out with the control page.
+    pub fn set_header(&mut self, header: Header) -> Result<()> {
+        self.set_page_size(header.page_size)?;
+        self.header = header;
+        self.set_journal(0)?;
+        // Written with the first commit, as a page that was never
+        // written does not pass its checksum once the file is longer
+        for &page_id in &self.bucket_to_page {
+            self.pool.pin_new(self.file_id, page_id)?;
+        }
+        Ok(())
+    }
+
+    /// Writes changed pages to a write-ahead log next to `filename`,
+    /// the file this was opened with, from now on.
+    pub fn use_wal(&self, filename: &str) -> Result<()> {
+        self.pool.use_wal(self.file_id, &wal_path(filename))
+    }
+
+    // Switches to shadow paging, with the page map at `map_root`, if
+    // the header asks for it.
+    fn set_journal(&mut self, map_root: usize) -> Result<()> {
+        if self.header.shadow_paging {
+            self.pool.use_shadow_paging(self.file_id, map_root)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_ctrlpage(&mut self) -> Result<(usize, usize, usize, usize)> {
+        self.get_ctrl_page()?;
+        let nbits = self.ctrl_field(CTRL_NBITS);
+        let nitems = self.ctrl_field(CTRL_NITEMS);
+        let nbuckets = self.ctrl_field(CTRL_NBUCKETS);
+
+        self.num_pages = self.ctrl_field(CTRL_NUM_PAGES);
+        let free_list_head = self.ctrl_field(CTRL_FREE_LIST);
         self.free_list =
             if free_list_head == 0 {
                 None
             } else {
                 Some(free_list_head)
             };
-        self.num_free =
-            bytearray_to_usize(self.ctrl_buffer.storage[40..48].to_vec());
-        self.bucket_to_page =
-            bytevec_to_usize_vec(self.ctrl_buffer.storage[48..PAGE_SIZE].to_vec());
-        (nbits, nitems, nbuckets)
-    }
-
-    pub fn write_ctrlpage(&mut self,
-                          (nbits, nitems, nbuckets):
-                          (usize, usize, usize)) {
-        self.get_ctrl_page();
-
-        let nbits_bytes = usize_to_bytearray(nbits);
-        let nitems_bytes = usize_to_bytearray(nitems);
-        let nbuckets_bytes = usize_to_bytearray(nbuckets);
-        let num_pages_bytes = usize_to_bytearray(self.num_pages);
-        let free_list_bytes = usize_to_bytearray(self.free_list.unwrap_or(0));
-        let num_free_bytes = usize_to_bytearray(self.num_free);
-        let bucket_to_page_bytevec = usize_vec_to_bytevec(self.bucket_to_page.clone());
-        let mut bucket_to_page_bytearray = vec![];
-        bucket_to_page_bytearray.write(&bucket_to_page_bytevec)
-            .expect("Write to ctrlpage failed");
-
-        println!("nbits: {:?} nitems: {:?} nbuckets: {:?}", nbits_bytes,
-                 nitems_bytes, nbuckets_bytes);
-        mem_move(&mut self.ctrl_buffer.storage[0..8],
-                 &nbits_bytes);
-        mem_move(&mut self.ctrl_buffer.storage[8..16],
-                 &nitems_bytes);
-        mem_move(&mut self.ctrl_buffer.storage[16..24],
-                 &nbuckets_bytes);
-        mem_move(&mut self.ctrl_buffer.storage[24..32],
-                 &num_pages_bytes);
-        mem_move(&mut self.ctrl_buffer.storage[32..40],
-                 &free_list_bytes);
-        mem_move(&mut self.ctrl_buffer.storage[40..48],
-                 &num_free_bytes);
-        mem_move(&mut self.ctrl_buffer.storage[48..PAGE_SIZE],
-                 &bucket_to_page_bytearray);
-        DbFile::write_page(&mut self.file,
-                           0,
-                           &self.ctrl_buffer.storage);
-    }
-
-    pub fn get_ctrl_page(&mut self) {
-        self.file.seek(SeekFrom::Start(0))
-            .expect("Could not seek to offset");
-        self.file.read(&mut self.ctrl_buffer.storage)
-            .expect("Could not read file");
-    }
-
-    fn bucket_to_page(&self, bucket_id: usize) -> usize {
-        self.bucket_to_page[bucket_id]
+        self.num_free = self.ctrl_field(CTRL_NUM_FREE);
+        let nbytes = self.ctrl_field(CTRL_NBYTES);
+        let dir_root = self.ctrl_field(CTRL_DIR_ROOT);
+        self.read_directory(dir_root)?;
+        if nbuckets != self.bucket_to_page.len() {
+            return Err(Error::Corrupt {
+                page_id: 0,
+                reason: "bucket count does not match bucket directory",
+            });
+        }
+        Ok((nbits, nitems, nbuckets, nbytes))
     }
 
-    fn search_buffer_pool(&self, page_id: usize) -> Option<usize> {
-        for (i, b) in self.buffers.iter().enumerate() {
-            if b.id == page_id {
-                return Some(i);
+    /// Load `bucket_to_page` from the directory chain starting at
+    /// `dir_root`.
+    fn read_directory(&mut self, dir_root: usize) -> Result<()> {
+        self.bucket_to_page = vec![];
+        self.dir_pages = vec![];
+        let entries_per_page = self.dir_entries_per_page();
+        let mut next_page = if dir_root != 0 { Some(dir_root) } else { None };
+        while let Some(page_id) = next_page {
+            let page = self.pool.pin(self.file_id, page_id)?;
+            if page.num_records > entries_per_page {
+                return Err(Error::Corrupt {
+                    page_id,
+                    reason: "directory page holds too many mappings",
+                });
+            }
+            for i in 0..page.num_records {
+                self.bucket_to_page.push(read_dir_entry(&page, i));
             }
+            self.dir_pages.push(page_id);
+            next_page = page.next;
         }
-        None
+        self.dir_synced = self.bucket_to_page.len();
+        Ok(())
     }
 
-    /// Reads page to self.buffer
-    pub fn fetch_page(&mut self, page_id: usize) -> usize {
-        let bufpool_index = self.search_buffer_pool(page_id);
-        match bufpool_index {
-            None => {
-                match self.buffers.pop_front() {
-                    Some(mut old_page) => {
-                        if old_page.dirty {
-                            old_page.write_header();
-                            DbFile::write_page(&self.file,
-                                               old_page.id,
-                                               &old_page.storage);
-                        }
-                    },
-                    _ => (),
+    /// Write `bucket_to_page` mappings that are not on disk yet to the
+    /// directory chain, adding directory pages as needed. If buckets
+    /// were removed, the chain is shortened instead.
+    fn sync_directory(&mut self) -> Result<()> {
+        let entries_per_page = self.dir_entries_per_page();
+        let len = self.bucket_to_page.len();
+        let num_dir_pages = len.div_ceil(entries_per_page);
+        if self.dir_pages.len() > num_dir_pages {
+            while self.dir_pages.len() > num_dir_pages {
+                if let Some(page_id) = self.dir_pages.pop() {
+                    self.free_page(page_id)?;
+                }
+            }
+            let last_page_id = self.dir_pages[num_dir_pages - 1];
+            self.pool.pin_mut(self.file_id, last_page_id)?.next = None;
+        }
+        // Merges may have dropped mappings from the end of the last
+        // page, which later splits have not written again
+        if self.dir_pages.len() == num_dir_pages {
+            let last_page_id = self.dir_pages[num_dir_pages - 1];
+            let last_records = len - (num_dir_pages - 1) * entries_per_page;
+            if self.pool.pin(self.file_id, last_page_id)?.num_records > last_records {
+                self.pool.pin_mut(self.file_id, last_page_id)?.num_records = last_records;
+            }
+        }
+
+        while self.dir_synced < self.bucket_to_page.len() {
+            let dir_index = self.dir_synced / entries_per_page;
+            if dir_index == self.dir_pages.len() {
+                let page_id = self.allocate_new_page()?;
+                if let Some(&last_page_id) = self.dir_pages.last() {
+                    self.pool.pin_mut(self.file_id, last_page_id)?.next = Some(page_id);
                 }
+                self.dir_pages.push(page_id);
+            }
+
+            let entry = self.dir_synced % entries_per_page;
+            let mut page = self.pool.pin_mut(self.file_id, self.dir_pages[dir_index])?;
+            write_dir_entry(&mut page, entry, self.bucket_to_page[self.dir_synced]);
+            page.num_records = entry + 1;
+            self.dir_synced += 1;
+        }
+        Ok(())
+    }
 
-                let offset = (page_id * PAGE_SIZE) as u64;
-                let mut new_page = Page::new(self.keysize, self.valsize);
-                new_page.id = page_id;
-                let buffer_index = NUM_BUFFERS - 1;
+    fn fill_ctrlpage(&mut self,
+                     (nbits, nitems, nbuckets, nbytes):
+                     (usize, usize, usize, usize)) -> Result<()> {
+        // May allocate pages, so do it before `num_pages` and
+        // `free_list` are written out.
+        self.sync_directory()?;
+
+        let next_slot = 1 - self.ctrl_slot;
+        mem_move(&mut self.ctrl_buffer.storage[slot_range(next_slot)][0..8], &MAGIC);
+        self.set_ctrl_field(CTRL_VERSION, FORMAT_VERSION);
+        let generation = self.generation + 1;
+        self.set_ctrl_field(CTRL_GENERATION, generation);
+        let Header { page_size, keysize, valsize, hasher, hash_key, shadow_paging } = self.header;
+        self.set_ctrl_field(CTRL_PAGE_SIZE, page_size);
+        self.set_ctrl_field(CTRL_KEYSIZE, keysize);
+        self.set_ctrl_field(CTRL_VALSIZE, valsize);
+        self.set_ctrl_field(CTRL_HASHER, hasher);
+        self.set_ctrl_field(CTRL_HASH_KEY, hash_key[0] as usize);
+        self.set_ctrl_field(CTRL_HASH_KEY + 1, hash_key[1] as usize);
+        self.set_ctrl_field(CTRL_SHADOW_PAGING, shadow_paging as usize);
+        self.set_ctrl_field(CTRL_NBITS, nbits);
+        self.set_ctrl_field(CTRL_NITEMS, nitems);
+        self.set_ctrl_field(CTRL_NBUCKETS, nbuckets);
+        let (num_pages, free_list, num_free) =
+            (self.num_pages, self.free_list.unwrap_or(0), self.num_free);
+        self.set_ctrl_field(CTRL_NUM_PAGES, num_pages);
+        self.set_ctrl_field(CTRL_FREE_LIST, free_list);
+        self.set_ctrl_field(CTRL_NUM_FREE, num_free);
+        self.set_ctrl_field(CTRL_NBYTES, nbytes);
+        let dir_root = *self.dir_pages.first().unwrap_or(&0);
+        self.set_ctrl_field(CTRL_DIR_ROOT, dir_root);
+        Ok(())
+    }
+
+    /// Writes the changed pages, then the control page referring to
+    /// them. With a write-ahead log or shadow paging, they are
+    /// committed together.
+    pub fn write_ctrlpage(&mut self, ctrl: (usize, usize, usize, usize)) -> Result<()> {
+        self.fill_ctrlpage(ctrl)?;
+        let next_slot = 1 - self.ctrl_slot;
+        self.pool.commit(self.file_id, &mut self.ctrl_buffer.storage, |ctrl, map_root| {
+            seal_slot(&mut ctrl[slot_range(next_slot)], map_root);
+            slot_range(next_slot)
+        })?;
+        self.ctrl_written();
+        Ok(())
+    }
 
-                self.file.seek(SeekFrom::Start(offset))
-                    .expect("Could not seek to offset");
-                self.file.read(&mut new_page.storage)
-                    .expect("Could not read file");
-                self.buffers.push_back(new_page);
-                self.buffers[buffer_index].read_header();
+    // The slot filled by `fill_ctrlpage` was written and is the newest
+    fn ctrl_written(&mut self) {
+        self.ctrl_slot = 1 - self.ctrl_slot;
+        self.generation += 1;
+    }
 
-                buffer_index
+    /// Reads the control page, and picks the slot with the newest
+    /// generation of those written completely.
+    pub fn get_ctrl_page(&mut self) -> Result<()> {
+        self.pool.read_uncached(self.file_id, 0, &mut self.ctrl_buffer.storage)?;
+        let newest = {
+            let storage = &self.ctrl_buffer.storage;
+            (0..2).filter(|&slot| valid_slot(&storage[slot_range(slot)]))
+                .max_by_key(|&slot| slot_field(&storage[slot_range(slot)], CTRL_GENERATION))
+        };
+        match newest {
+            Some(slot) => {
+                self.ctrl_slot = slot;
+                self.generation = self.ctrl_field(CTRL_GENERATION);
+                let version = self.ctrl_field(CTRL_VERSION);
+                if version != FORMAT_VERSION {
+                    return Err(Error::UnsupportedVersion { version });
+                }
+                Ok(())
+            },
+            None => {
+                // Tell apart files that are no table, or were written
+                // by a version without checksums
+                let slot = &self.ctrl_buffer.storage[slot_range(0)];
+                if slot[0..8] != MAGIC[..] {
+                    return Err(Error::NotATable);
+                }
+                let version = slot_field(slot, CTRL_VERSION);
+                if version != FORMAT_VERSION {
+                    return Err(Error::UnsupportedVersion { version });
+                }
+                Err(Error::Corrupt { page_id: 0, reason: "no valid control page" })
             },
-            Some(p) => p,
         }
     }
 
-    /// Writes data in `data` into page `page_id` in file.
-    pub fn write_page(mut file: &File, page_id: usize, data: &[u8]) {
-        let offset = (page_id * PAGE_SIZE) as u64;
-        file.seek(SeekFrom::Start(offset))
-            .expect("Could not seek to offset");
-        file.write(data).expect("write failed");
-        file.flush().expect("flush failed");
+    pub fn bucket_to_page(&self, bucket_id: usize) -> usize {
+        self.bucket_to_page[bucket_id]
     }
 
     /// Write record but don't increment `num_records`. Used when
-    /// updating already existing record.
+    /// updating already existing record. Returns false if the new
+    /// record no longer fits in the page.
     pub fn write_record(&mut self,
                         page_id: usize,
                         row_num: usize,
                         key: &[u8],
-                        val: &[u8]) {
-        let buffer_index = self.fetch_page(page_id);
-        self.buffers[buffer_index].dirty = true;
-        self.buffers[buffer_index].write_record(row_num, key, val);
+                        val: &[u8]) -> Result<bool> {
+        let mut page = self.pool.pin_mut(self.file_id, page_id)?;
+        Ok(page.write_record(row_num, key, val))
     }
 
     /// Write record and increment `num_records`. Used when inserting
     /// new record.
-    pub fn write_record_incr(&mut self, page_id: usize, row_num: usize,
-                             key: &[u8], val: &[u8]) {
-        let buffer_index = self.fetch_page(page_id);
-        self.buffers[buffer_index].incr_num_records();
-        self.write_record(page_id, row_num, key, val);
+    pub fn write_record_incr(&mut self, page_id: usize,
+                             key: &[u8], val: &[u8]) -> Result<()> {
+        self.pool.pin_mut(self.file_id, page_id)?.insert_record(key, val);
+        Ok(())
     }
 
     /// Searches for `key` in `bucket`. A bucket is a linked list of
@@ -232,35 +553,34 @@ impl DbFile {
     /// (page_id, row_num, val).
     ///
     /// If key is not present and:
-    ///   1. there is enough space in last page, returns (page_id, row_num, None)
+    ///   1. a page has enough space for `key` and a value of
+    ///      `val_len` bytes, returns (page_id, row_num, None)
     ///
-    ///   2. there is not enough space in last page, returns
+    ///   2. there is not enough space in any page, returns
     ///      (last_page_id, None, None)
-    pub fn search_bucket(&mut self, bucket_id: usize, key: &[u8]) -> SearchResult {
+    pub fn search_bucket(&mut self, bucket_id: usize, key: &[u8],
+                         val_len: usize) -> Result<SearchResult> {
         let mut page_id = self.bucket_to_page(bucket_id);
-        let mut buffer_index;
         let mut first_free_row = SearchResult {
             page_id: None,
             row_num: None,
             val: None,
         };
         loop {
-            buffer_index = self.fetch_page(page_id);
-            let next_page = self.buffers[buffer_index].next;
-            let page_records = self.all_records_in_page(page_id);
-
-            let len = page_records.len();
-            for (row_num, (k,v)) in page_records.into_iter().enumerate() {
-                if slices_eq(&k, key) {
-                    return SearchResult{
+            let page = self.pool.pin(self.file_id, page_id)?;
+            let len = page.num_records;
+            for row_num in 0..len {
+                let (k, v) = page.read_record(row_num);
+                if slices_eq(k, key) {
+                    return Ok(SearchResult{
                         page_id: Some(page_id),
                         row_num: Some(row_num),
-                        val: Some(v)
-                    }
+                        val: Some(v.to_vec())
+                    })
                 }
             }
 
-            let row_num = if len < self.records_per_page {
+            let row_num = if page.fits(key.len(), val_len) {
                 Some(len)
             } else {
                 None
@@ -273,192 +593,312 @@ impl DbFile {
                 (None, _) => {
                     first_free_row = SearchResult {
                         page_id: Some(page_id),
-                        row_num: row_num,
+                        row_num,
                         val: None,
                     }
                 },
                 _ => (),
             }
 
-            if let Some(p) = next_page {
+            if let Some(p) = page.next {
                 page_id = p;
             } else {
                 break;
             }
         }
 
-        first_free_row
-    }
-
-    /// Add a new overflow page to a `bucket`.
-    pub fn allocate_overflow(&mut self, bucket_id: usize,
-                             last_page_id: usize) -> (usize, usize) {
-        let physical_index = self.allocate_new_page();
-
-        let new_page_buffer_index = self.fetch_page(physical_index);
-        self.buffers[new_page_buffer_index].next = None;
-        self.buffers[new_page_buffer_index].dirty = true;
-
-        // Write next of old page
-        let old_page_buffer_index = self.fetch_page(last_page_id);
-        self.buffers[old_page_buffer_index].next = Some(physical_index);
-        self.buffers[old_page_buffer_index].dirty = true;
-
-        println!("setting next of buffer_id {}(page_id: {}) to {:?}",
-                 bucket_id,
-                 self.buffers[old_page_buffer_index].id,
-                 self.buffers[old_page_buffer_index].next);
-
-        (physical_index, 0)
+        Ok(first_free_row)
     }
 
-    /// Write out page in bufferpool to file.
-    pub fn write_buffer_page(&mut self, buffer_index: usize) {
-        // Ignore page 0(ctrlpage)
-        if self.buffers[buffer_index].id != 0 {
-            self.buffers[buffer_index].dirty = false;
-            self.buffers[buffer_index].write_header();
-            DbFile::write_page(&mut self.file,
-                               self.buffers[buffer_index].id,
-                               &self.buffers[buffer_index].storage);
-        }
+    /// Add a new overflow page after `last_page_id`, the last page of
+    /// a bucket.
+    pub fn allocate_overflow(&mut self, last_page_id: usize) -> Result<(usize, usize)> {
+        let physical_index = self.allocate_new_page()?;
+        self.pool.pin_mut(self.file_id, last_page_id)?.next = Some(physical_index);
+        Ok((physical_index, 0))
     }
 
-    fn all_records_in_page(&mut self, page_id: usize)
-                           -> Vec<(Vec<u8>, Vec<u8>)> {
-        let buffer_index = self.fetch_page(page_id);
-        let mut page_records = vec![];
-        for i in 0..self.buffers[buffer_index].num_records {
-            let (k, v) = self.buffers[buffer_index].read_record(i);
-            let (dk, dv) = (k.to_vec(), v.to_vec());
-            page_records.push((dk, dv));
+    /// Add a record to `bucket_id` without looking for its key,
+    /// allocating an overflow page if no page in the chain has room.
+    pub fn append_record(&mut self, bucket_id: usize,
+                         key: &[u8], val: &[u8]) -> Result<()> {
+        let mut page_id = self.bucket_to_page(bucket_id);
+        loop {
+            let next_page = {
+                let mut page = self.pool.pin_mut(self.file_id, page_id)?;
+                if page.fits(key.len(), val.len()) {
+                    page.insert_record(key, val);
+                    return Ok(());
+                }
+                page.next
+            };
+            page_id = match next_page {
+                Some(next_page_id) => next_page_id,
+                None => self.allocate_overflow(page_id)?.0,
+            };
         }
-
-        page_records
     }
 
     /// Returns a vec of (page_id, records_in_vec). ie. each inner
     /// vector represents the records in a page in the bucket.
-    fn all_records_in_bucket(&mut self, bucket_id: usize)
-                             -> Vec<(usize, Vec<(Vec<u8>,Vec<u8>)>)> {
-        let first_page_id = self.bucket_to_page(bucket_id);
-        let buffer_index = self.fetch_page(first_page_id);
+    fn all_records_in_bucket(&self, bucket_id: usize) -> Result<BucketRecords> {
         let mut records = Vec::new();
-        records.push((self.buffers[buffer_index].id,
-                      self.all_records_in_page(first_page_id)));
-
-        let mut next_page = self.buffers[buffer_index].next;
+        let mut next_page = Some(self.bucket_to_page(bucket_id));
         while let Some(page_id) = next_page {
-            if page_id == 0 {
-                break;
-            }
-
-            let buffer_index = self.fetch_page(page_id);
-            records.push((page_id,
-                          self.all_records_in_page(page_id)));
-
-            next_page = self.buffers[buffer_index].next;
+            let page = self.pool.pin(self.file_id, page_id)?;
+            records.push((page_id, page_records(&page)));
+            next_page = page.next;
         }
+        Ok(records)
+    }
 
-        records
+    /// Returns all records in the chain of `bucket_id`.
+    pub fn bucket_records(&mut self, bucket_id: usize)
+                          -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(flatten(self.all_records_in_bucket(bucket_id)?))
     }
 
     /// Allocate a new page. If available uses recycled overflow
     /// pages.
-    fn allocate_new_page(&mut self) -> usize {
-        let p = self.free_list;
-        let page_id = p.expect("no page in free_list");
-        println!("[allocate_new_page] allocating page_id: {}", page_id);
-        let buffer_index = self.fetch_page(page_id);
-
-        self.free_list = match self.buffers[buffer_index].next {
-            Some(0) | None => {
-                self.num_pages += 1;
-                Some(self.num_pages)
+    fn allocate_new_page(&mut self) -> Result<usize> {
+        let page_id = match self.free_list {
+            Some(page_id) => page_id,
+            None => return Err(Error::Corrupt {
+                page_id: 0,
+                reason: "free list is empty",
+            }),
+        };
+        // Pages from `num_pages` on were never allocated, though after
+        // a crash the file may still hold stale ones or holes there
+        let mut page = if page_id < self.num_pages {
+            self.pool.pin_mut(self.file_id, page_id)?
+        } else {
+            self.pool.pin_new(self.file_id, page_id)?
+        };
+        self.free_list = match page.next {
+            Some(next) if next != 0 && page_id < self.num_pages => {
+                self.num_free = self.num_free.checked_sub(1).ok_or(Error::Corrupt {
+                    page_id,
+                    reason: "free list is longer than its count",
+                })?;
+                Some(next)
             },
             _ => {
-                self.num_free -= 1;
-                self.buffers[buffer_index].next
+                self.num_pages = self.num_pages.max(page_id) + 1;
+                Some(self.num_pages)
             },
         };
+        // A recycled page still holds its old records on disk, so the
+        // empty page must be written back even if nothing is added.
+        page.clear();
+        Ok(page_id)
+    }
+
+    /// Moves the records of `bucket_id` for which `to_new` returns
+    /// true to `new_bucket_id`, which must be empty. The chain is read
+    /// one page at a time, and each page is emptied as soon as it is
+    /// read. Emptied overflow pages are reused when either bucket
+    /// needs another page, and those left over are added to
+    /// `free_list`.
+    pub fn split_bucket<F>(&mut self, bucket_id: usize, new_bucket_id: usize,
+                           to_new: F) -> Result<()>
+        where F: Fn(&[u8]) -> bool
+    {
+        let first_page_id = self.bucket_to_page(bucket_id);
+        let mut old_tail = first_page_id;
+        let mut new_tail = self.bucket_to_page(new_bucket_id);
+        let mut spare_pages = vec![];
+
+        let mut next_page = Some(first_page_id);
+        while let Some(page_id) = next_page {
+            let records = {
+                let mut page = self.pool.pin_mut(self.file_id, page_id)?;
+                next_page = page.next;
+                let records = page_records(&page);
+                page.clear();
+                records
+            };
+            if page_id != first_page_id {
+                spare_pages.push(page_id);
+            }
 
-        let new_page = Page::new(self.keysize, self.valsize);
-        mem::replace(&mut self.buffers[buffer_index], new_page);
-        self.buffers[buffer_index].id = page_id;
-        self.buffers[buffer_index].dirty = false;
-        self.buffers[buffer_index].next = None;
-
-        page_id
-    }
-
-    /// Empties out root page for bucket. Overflow pages are added to
-    /// `free_list`
-    pub fn clear_bucket(&mut self, bucket_id: usize) -> Vec<(Vec<u8>,Vec<u8>)> {
-        let all_records = self.all_records_in_bucket(bucket_id);
-        let records = flatten(all_records.clone());
-
-        // Add overflow pages to free_list
-        let bucket_len = all_records.len();
-        if bucket_len > 1 {
-            // second page onwards are overflow pages
-            let (second_page_id, _) = all_records[1];
-            println!("[clear_bucket] adding overflow chain starting page {} to free_list", second_page_id);
-            let temp = self.free_list;
-            self.free_list = Some(second_page_id);
-
-            let second_page_buffer_index =
-                self.fetch_page(second_page_id);
-            // overflow pages only
-            self.num_free += bucket_len - 1;
-            self.buffers[second_page_buffer_index].next = temp;
+            // Records that stay never need more pages than were read
+            // so far, so they are not written over unread records.
+            for (k, v) in records {
+                if to_new(&k) {
+                    new_tail = self.append_to_tail(new_tail, &k, &v, &mut spare_pages)?;
+                } else {
+                    old_tail = self.append_to_tail(old_tail, &k, &v, &mut spare_pages)?;
+                }
+            }
         }
 
-        let page_id = self.bucket_to_page(bucket_id);
-        let buffer_index = self.fetch_page(page_id);
-        let new_page = Page::new(self.keysize, self.valsize);
-        mem::replace(&mut self.buffers[buffer_index], new_page);
-        self.buffers[buffer_index].id = page_id;
-        self.buffers[buffer_index].dirty = false;
-        self.write_buffer_page(buffer_index);
+        for page_id in spare_pages {
+            self.free_page(page_id)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a record to `page_id`, the last page of a chain. If it
+    /// does not fit, a page from `spare_pages` or a newly allocated
+    /// one is linked after it and the record goes there. Returns the
+    /// page the record was added to.
+    fn append_to_tail(&mut self, page_id: usize, key: &[u8], val: &[u8],
+                      spare_pages: &mut Vec<usize>) -> Result<usize> {
+        {
+            let mut page = self.pool.pin_mut(self.file_id, page_id)?;
+            if page.fits(key.len(), val.len()) {
+                page.insert_record(key, val);
+                return Ok(page_id);
+            }
+        }
 
-        records
+        let new_page_id = match spare_pages.pop() {
+            Some(spare_page_id) => spare_page_id,
+            None => self.allocate_new_page()?,
+        };
+        self.pool.pin_mut(self.file_id, page_id)?.next = Some(new_page_id);
+        self.write_record_incr(new_page_id, key, val)?;
+        Ok(new_page_id)
     }
 
-    pub fn allocate_new_bucket(&mut self) {
-        let page_id = self.allocate_new_page();
+    /// Removes the record at `row_num` in page `page_id` of
+    /// `bucket_id`. If it fits, the last record of the bucket chain
+    /// is moved into the freed space so that records stay packed
+    /// towards the start of the chain. If this empties the last
+    /// overflow page, it is unlinked from the chain and added to
+    /// `free_list`.
+    pub fn remove_record(&mut self, bucket_id: usize, page_id: usize,
+                         row_num: usize) -> Result<()> {
+        let mut prev_page_id = None;
+        let mut last_page_id = self.bucket_to_page(bucket_id);
+        while let Some(next) = self.pool.pin(self.file_id, last_page_id)?.next {
+            prev_page_id = Some(last_page_id);
+            last_page_id = next;
+        }
+
+        let mut page = self.pool.pin_mut(self.file_id, page_id)?;
+        page.remove_record(row_num);
+        if page_id != last_page_id {
+            let mut last_page = self.pool.pin_mut(self.file_id, last_page_id)?;
+            let last_row = last_page.num_records - 1;
+            let (k, v) = last_page.read_record(last_row);
+            if page.fits(k.len(), v.len()) {
+                page.insert_record(k, v);
+                last_page.remove_record(last_row);
+            }
+        }
+        drop(page);
+
+        let last_page_empty = self.pool.pin(self.file_id, last_page_id)?.num_records == 0;
+
+        // The first page of a bucket is never freed, only overflow
+        // pages are.
+        if let (true, Some(prev_page_id)) = (last_page_empty, prev_page_id) {
+            self.pool.pin_mut(self.file_id, prev_page_id)?.next = None;
+            self.free_page(last_page_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Push page `page_id` onto `free_list`.
+    fn free_page(&mut self, page_id: usize) -> Result<()> {
+        let mut page = self.pool.pin_mut(self.file_id, page_id)?;
+        page.clear();
+        page.next = self.free_list;
+        self.free_list = Some(page_id);
+        self.num_free += 1;
+        Ok(())
+    }
+
+    pub fn allocate_new_bucket(&mut self) -> Result<()> {
+        let page_id = self.allocate_new_page()?;
         self.bucket_to_page.push(page_id);
+        Ok(())
     }
 
-    pub fn close(&mut self) {
-        for b in 0..NUM_BUFFERS {
-            self.write_buffer_page(b);
+    /// Drops the last bucket and returns its records. All of its
+    /// pages are added to `free_list`.
+    pub fn remove_last_bucket(&mut self) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let bucket_id = self.bucket_to_page.len() - 1;
+        let all_records = self.all_records_in_bucket(bucket_id)?;
+        for &(page_id, _) in all_records.iter() {
+            self.free_page(page_id)?;
         }
+        self.bucket_to_page.pop();
+        // A split may put another page in its place before the
+        // directory is synced
+        self.dir_synced = self.dir_synced.min(self.bucket_to_page.len());
+        Ok(flatten(all_records))
+    }
+
+    /// Writes the dirty pages, then the control page, and waits for
+    /// them to reach the disk. See `BufferPool::checkpoint`.
+    pub fn flush(&mut self, ctrl: (usize, usize, usize, usize)) -> Result<()> {
+        // Before the pages are written, as it may change some
+        self.fill_ctrlpage(ctrl)?;
+        let next_slot = 1 - self.ctrl_slot;
+        self.pool.checkpoint(self.file_id, &mut self.ctrl_buffer.storage, |ctrl, map_root| {
+            seal_slot(&mut ctrl[slot_range(next_slot)], map_root);
+            slot_range(next_slot)
+        })?;
+        self.ctrl_written();
+        Ok(())
+    }
+}
+
+impl Drop for DbFile {
+    fn drop(&mut self) {
+        self.pool.unregister(self.file_id);
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use disk;
     use DbFile;
+    use LinHashOptions;
     use std::fs;
 
     #[test]
     fn dbfile_tests () {
-        let mut bp = DbFile::new("/tmp/dbfile_tests", 4, 4);
+        let mut bp = DbFile::new("/tmp/dbfile_tests", &LinHashOptions::new()).unwrap();
         let bark = b"bark";
         let krab = b"krab";
         // write to page 1
-        bp.write_record(1, 14, bark, krab);
-        assert_eq!(bp.buffers[disk::NUM_BUFFERS-1].read_record(14),
-                   (&bark[..], &krab[..]));
-        bp.close();
+        bp.write_record_incr(1, bark, krab).unwrap();
+        assert_eq!(bp.pin(1).unwrap().read_record(0), (&bark[..], &krab[..]));
+        bp.flush((1, 0, 2, 0)).unwrap();
 
-        let mut bp2 = DbFile::new("/tmp/dbfile_tests", 4, 4);
+        let bp2 = DbFile::new("/tmp/dbfile_tests", &LinHashOptions::new()).unwrap();
         // read from page 1
-        let buffer_index = bp2.fetch_page(1);
-        assert_eq!(bp2.buffers[buffer_index].read_record(14),
-                   (&bark[..], &krab[..]));
+        assert_eq!(bp2.pin(1).unwrap().read_record(0), (&bark[..], &krab[..]));
 
         fs::remove_file("/tmp/dbfile_tests").ok();
     }
+
+    #[test]
+    fn split_bucket() {
+        fs::remove_file("/tmp/split_bucket").ok();
+        let mut bp = DbFile::new("/tmp/split_bucket", &LinHashOptions::new()).unwrap();
+        for i in 0..1000u32 {
+            bp.append_record(0, &i.to_ne_bytes(), &[7; 40]).unwrap();
+        }
+        bp.allocate_new_bucket().unwrap();
+        let num_pages = bp.num_pages;
+
+        let odd = |k: &[u8]| u32::from_ne_bytes([k[0], k[1], k[2], k[3]]) % 2 == 1;
+        bp.split_bucket(0, 2, odd).unwrap();
+        // Both halves fit in the pages of the old chain and the new
+        // bucket's page
+        assert_eq!(bp.num_pages, num_pages);
+        assert_eq!(bp.num_free, 0);
+
+        let stayed = bp.bucket_records(0).unwrap();
+        let moved = bp.bucket_records(2).unwrap();
+        assert_eq!((stayed.len(), moved.len()), (500, 500));
+        assert!(stayed.iter().all(|(k, v)| !odd(k) && v[..] == [7; 40][..]));
+        assert!(moved.iter().all(|(k, v)| odd(k) && v[..] == [7; 40][..]));
+        fs::remove_file("/tmp/split_bucket").ok();
+    }
 }
@@ -0,0 +1,81 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+/// Errors returned by `LinHash` and `DbFile` operations.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from or writing to the underlying file failed.
+    Io(io::Error),
+    /// The file does not hold what we expect to find in `page_id`.
+    Corrupt { page_id: usize, reason: &'static str },
+    /// Key is longer than the table allows.
+    KeyTooLarge { len: usize, max: usize },
+    /// Value is longer than the table allows.
+    ValueTooLarge { len: usize, max: usize },
+    /// `put` was called with a key that is already present. Use
+    /// `update` to change its value.
+    KeyExists,
+    /// The file does not start with the magic number of a table.
+    NotATable,
+    /// The file was written in a format this version cannot read.
+    UnsupportedVersion { version: usize },
+    /// The table was created with a different `param` than the one
+    /// it is being opened with.
+    Mismatch { param: &'static str, requested: usize, stored: usize },
+    /// The table was created with a hash function that is not built
+    /// in. Open it with `LinHash::open_with_hasher` instead.
+    UnknownHasher { id: usize },
+    /// An option passed to `LinHashOptions` is out of range.
+    InvalidOption { option: &'static str, reason: &'static str },
+    /// A page had to be read in, but every page in the buffer pool is
+    /// pinned.
+    PoolExhausted,
+    /// Page `page_id` is pinned already, and cannot be pinned again
+    /// until its guard is dropped.
+    PageBusy { page_id: usize },
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Corrupt { page_id, reason } =>
+                write!(f, "page {} is corrupt: {}", page_id, reason),
+            Error::KeyTooLarge { len, max } =>
+                write!(f, "key of {} bytes exceeds maximum of {} bytes", len, max),
+            Error::ValueTooLarge { len, max } =>
+                write!(f, "value of {} bytes exceeds maximum of {} bytes", len, max),
+            Error::KeyExists => write!(f, "key already exists"),
+            Error::NotATable => write!(f, "file is not a linhash table"),
+            Error::UnsupportedVersion { version } =>
+                write!(f, "unsupported file format version {}", version),
+            Error::Mismatch { param, requested, stored } =>
+                write!(f, "table was created with {} {}, not {}", param, stored, requested),
+            Error::UnknownHasher { id } =>
+                write!(f, "table was created with unknown hash function {}", id),
+            Error::InvalidOption { option, reason } =>
+                write!(f, "invalid {}: {}", option, reason),
+            Error::PoolExhausted => write!(f, "every page in the buffer pool is pinned"),
+            Error::PageBusy { page_id } => write!(f, "page {} is already pinned", page_id),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
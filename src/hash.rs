@@ -0,0 +1,292 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// Hash function used to place keys in buckets. A table must be
+/// hashed the same way for as long as it exists, so implementations
+/// must give the same result on every platform and every release.
+pub trait KeyHasher: Send {
+    /// Identifies the hash function in the file header, so that a
+    /// table is never opened with a different one. Ids below 256 are
+    /// reserved for the built-in hashers.
+    fn id(&self) -> usize;
+
+    /// Secret the hash function is seeded with. It is stored in the
+    /// control page when a table is created, and built-in hashers are
+    /// seeded from there when the table is reopened.
+    fn key(&self) -> [u64; 2] {
+        [0, 0]
+    }
+
+    fn hash(&self, key: &[u8]) -> u64;
+}
+
+/// Returns the built-in hasher identified by `id`, seeded with `key`.
+pub fn builtin_hasher(id: usize, key: [u64; 2]) -> Option<Box<dyn KeyHasher>> {
+    match id {
+        Fnv1a::ID => Some(Box::new(Fnv1a)),
+        XxHash64::ID => Some(Box::new(XxHash64)),
+        SipHash13::ID => Some(Box::new(SipHash13)),
+        KeyedSipHash13::ID => Some(Box::new(KeyedSipHash13::new(key))),
+        _ => None,
+    }
+}
+
+fn read_u64(b: &[u8]) -> u64 {
+    let mut a = [0; 8];
+    a.copy_from_slice(&b[..8]);
+    u64::from_le_bytes(a)
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    let mut a = [0; 4];
+    a.copy_from_slice(&b[..4]);
+    u32::from_le_bytes(a)
+}
+
+/// 64-bit FNV-1a. Very cheap for short keys, but weak.
+pub struct Fnv1a;
+
+impl Fnv1a {
+    pub const ID: usize = 1;
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+}
+
+impl KeyHasher for Fnv1a {
+    fn id(&self) -> usize {
+        Fnv1a::ID
+    }
+
+    fn hash(&self, key: &[u8]) -> u64 {
+        key.iter().fold(Fnv1a::OFFSET_BASIS, |h, &b| {
+            (h ^ u64::from(b)).wrapping_mul(Fnv1a::PRIME)
+        })
+    }
+}
+
+/// xxHash64 with a seed of 0. Fast for keys of any length.
+pub struct XxHash64;
+
+impl XxHash64 {
+    pub const ID: usize = 2;
+    const P1: u64 = 0x9e37_79b1_85eb_ca87;
+    const P2: u64 = 0xc2b2_ae3d_27d4_eb4f;
+    const P3: u64 = 0x1656_67b1_9e37_79f9;
+    const P4: u64 = 0x85eb_ca77_c2b2_ae63;
+    const P5: u64 = 0x27d4_eb2f_1656_67c5;
+
+    fn round(acc: u64, input: u64) -> u64 {
+        acc.wrapping_add(input.wrapping_mul(XxHash64::P2))
+            .rotate_left(31)
+            .wrapping_mul(XxHash64::P1)
+    }
+
+    fn merge_round(acc: u64, val: u64) -> u64 {
+        (acc ^ XxHash64::round(0, val))
+            .wrapping_mul(XxHash64::P1)
+            .wrapping_add(XxHash64::P4)
+    }
+}
+
+impl KeyHasher for XxHash64 {
+    fn id(&self) -> usize {
+        XxHash64::ID
+    }
+
+    fn hash(&self, key: &[u8]) -> u64 {
+        let (p1, p2, p3, p4, p5) =
+            (XxHash64::P1, XxHash64::P2, XxHash64::P3, XxHash64::P4, XxHash64::P5);
+        let mut rest = key;
+
+        let mut h = if key.len() >= 32 {
+            let mut v = [p1.wrapping_add(p2), p2, 0, 0u64.wrapping_sub(p1)];
+            while rest.len() >= 32 {
+                for (i, acc) in v.iter_mut().enumerate() {
+                    *acc = XxHash64::round(*acc, read_u64(&rest[i*8..]));
+                }
+                rest = &rest[32..];
+            }
+            let mut h = v[0].rotate_left(1)
+                .wrapping_add(v[1].rotate_left(7))
+                .wrapping_add(v[2].rotate_left(12))
+                .wrapping_add(v[3].rotate_left(18));
+            for &acc in v.iter() {
+                h = XxHash64::merge_round(h, acc);
+            }
+            h
+        } else {
+            p5
+        };
+        h = h.wrapping_add(key.len() as u64);
+
+        while rest.len() >= 8 {
+            h ^= XxHash64::round(0, read_u64(rest));
+            h = h.rotate_left(27).wrapping_mul(p1).wrapping_add(p4);
+            rest = &rest[8..];
+        }
+        if rest.len() >= 4 {
+            h ^= u64::from(read_u32(rest)).wrapping_mul(p1);
+            h = h.rotate_left(23).wrapping_mul(p2).wrapping_add(p3);
+            rest = &rest[4..];
+        }
+        for &b in rest {
+            h ^= u64::from(b).wrapping_mul(p5);
+            h = h.rotate_left(11).wrapping_mul(p1);
+        }
+
+        h ^= h >> 33;
+        h = h.wrapping_mul(p2);
+        h ^= h >> 29;
+        h = h.wrapping_mul(p3);
+        h ^= h >> 32;
+        h
+    }
+}
+
+/// SipHash-1-3 with a fixed all-zero key. This is the default.
+pub struct SipHash13;
+
+impl SipHash13 {
+    pub const ID: usize = 3;
+
+    fn sip_round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13);
+        v[1] ^= v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16);
+        v[3] ^= v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21);
+        v[3] ^= v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17);
+        v[1] ^= v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+
+    fn hash_with_key(k0: u64, k1: u64, key: &[u8]) -> u64 {
+        let mut v = [k0 ^ 0x736f_6d65_7073_6575,
+                     k1 ^ 0x646f_7261_6e64_6f6d,
+                     k0 ^ 0x6c79_6765_6e65_7261,
+                     k1 ^ 0x7465_6462_7974_6573];
+
+        let mut chunks = key.chunks_exact(8);
+        for chunk in &mut chunks {
+            let m = read_u64(chunk);
+            v[3] ^= m;
+            SipHash13::sip_round(&mut v);
+            v[0] ^= m;
+        }
+
+        // Last block holds the remaining bytes and the length
+        let mut b = (key.len() as u64) << 56;
+        for (i, &byte) in chunks.remainder().iter().enumerate() {
+            b |= u64::from(byte) << (8 * i);
+        }
+        v[3] ^= b;
+        SipHash13::sip_round(&mut v);
+        v[0] ^= b;
+
+        v[2] ^= 0xff;
+        for _ in 0..3 {
+            SipHash13::sip_round(&mut v);
+        }
+        v[0] ^ v[1] ^ v[2] ^ v[3]
+    }
+}
+
+impl KeyHasher for SipHash13 {
+    fn id(&self) -> usize {
+        SipHash13::ID
+    }
+
+    fn hash(&self, key: &[u8]) -> u64 {
+        SipHash13::hash_with_key(0, 0, key)
+    }
+}
+
+/// SipHash-1-3 with a secret 128-bit key. Unless the key is known,
+/// which bucket a key lands in cannot be predicted, so clients cannot
+/// flood a single bucket with keys.
+pub struct KeyedSipHash13 {
+    key: [u64; 2],
+}
+
+impl KeyedSipHash13 {
+    pub const ID: usize = 4;
+
+    pub fn new(key: [u64; 2]) -> KeyedSipHash13 {
+        KeyedSipHash13 { key }
+    }
+
+    /// A hasher with a key drawn from the randomness the standard
+    /// library seeds `HashMap`s with.
+    pub fn random() -> KeyedSipHash13 {
+        let random_u64 = |i: u64| {
+            let mut h = RandomState::new().build_hasher();
+            h.write_u64(i);
+            h.finish()
+        };
+        KeyedSipHash13::new([random_u64(0), random_u64(1)])
+    }
+}
+
+impl KeyHasher for KeyedSipHash13 {
+    fn id(&self) -> usize {
+        KeyedSipHash13::ID
+    }
+
+    fn key(&self) -> [u64; 2] {
+        self.key
+    }
+
+    fn hash(&self, key: &[u8]) -> u64 {
+        SipHash13::hash_with_key(self.key[0], self.key[1], key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hash::*;
+
+    // These must never change: tables on disk depend on them.
+    #[test]
+    fn known_values() {
+        assert_eq!(Fnv1a.hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(Fnv1a.hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(Fnv1a.hash(b"foobar"), 0x8594_4171_f739_67e8);
+
+        assert_eq!(XxHash64.hash(b""), 0xef46_db37_51d8_e999);
+        assert_eq!(XxHash64.hash(b"a"), 0xd24e_c4f1_a98c_6e5b);
+        assert_eq!(XxHash64.hash(b"abc"), 0x44bc_2cf5_ad77_0999);
+        assert_eq!(XxHash64.hash(b"Nobody inspects the spammish repetition"),
+                   0xfbce_a83c_8a37_8bf1);
+
+        assert_eq!(SipHash13.hash(b""), 0xd1fb_a762_150c_532c);
+        assert_eq!(SipHash13.hash(b"a"), 0x4074_48d2_b89b_1813);
+        assert_eq!(SipHash13.hash(b"hello world, this is a longer key"),
+                   0x3cd2_c4f9_3c8d_fa05);
+    }
+
+    #[test]
+    fn builtin_ids() {
+        for &id in &[Fnv1a::ID, XxHash64::ID, SipHash13::ID, KeyedSipHash13::ID] {
+            assert_eq!(builtin_hasher(id, [0, 0]).unwrap().id(), id);
+        }
+        assert!(builtin_hasher(0, [0, 0]).is_none());
+    }
+
+    #[test]
+    fn keyed_siphash() {
+        assert_eq!(KeyedSipHash13::new([0, 0]).hash(b"a"), SipHash13.hash(b"a"));
+
+        let a = KeyedSipHash13::random();
+        let b = KeyedSipHash13::random();
+        assert!(a.key() != b.key());
+        assert!(a.hash(b"a") != b.hash(b"a"));
+        let rebuilt = builtin_hasher(KeyedSipHash13::ID, a.key()).unwrap();
+        assert_eq!(rebuilt.hash(b"a"), a.hash(b"a"));
+    }
+}
@@ -0,0 +1,110 @@
+use error::Result;
+use LinHash;
+
+/// Iterator over all records of a `LinHash`, created by
+/// `LinHash::iter`. Buckets are visited in order, following each
+/// bucket's overflow chain, and records are read from the buffer pool
+/// one at a time.
+pub struct Iter<'a> {
+    table: &'a mut LinHash,
+    // next bucket to start on, once the current chain is done
+    bucket: usize,
+    // page in the current chain, and the next row to read from it
+    page_id: Option<usize>,
+    row_num: usize,
+}
+
+impl<'a> Iter<'a> {
+    pub fn new(table: &'a mut LinHash) -> Iter<'a> {
+        Iter {
+            table,
+            bucket: 0,
+            page_id: None,
+            row_num: 0,
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            let page_id = match self.page_id {
+                Some(page_id) => page_id,
+                None => {
+                    if self.bucket >= self.table.nbuckets {
+                        return Ok(None);
+                    }
+                    let page_id = self.table.buckets.bucket_to_page(self.bucket);
+                    self.bucket += 1;
+                    self.page_id = Some(page_id);
+                    self.row_num = 0;
+                    page_id
+                },
+            };
+
+            let page = self.table.buckets.pin(page_id)?;
+            if self.row_num < page.num_records {
+                let (k, v) = page.read_record(self.row_num);
+                self.row_num += 1;
+                return Ok(Some((k.to_vec(), v.to_vec())));
+            }
+
+            self.page_id = page.next;
+            self.row_num = 0;
+        }
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_record() {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => None,
+            Err(e) => {
+                // Don't keep failing on the same page
+                self.page_id = None;
+                self.bucket = self.table.nbuckets;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+/// Iterator over the keys of a `LinHash`, created by `LinHash::keys`.
+pub struct Keys<'a> {
+    inner: Iter<'a>,
+}
+
+impl<'a> Keys<'a> {
+    pub fn new(table: &'a mut LinHash) -> Keys<'a> {
+        Keys { inner: Iter::new(table) }
+    }
+}
+
+impl<'a> Iterator for Keys<'a> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|r| r.map(|(k, _)| k))
+    }
+}
+
+/// Iterator over the values of a `LinHash`, created by
+/// `LinHash::values`.
+pub struct Values<'a> {
+    inner: Iter<'a>,
+}
+
+impl<'a> Values<'a> {
+    pub fn new(table: &'a mut LinHash) -> Values<'a> {
+        Values { inner: Iter::new(table) }
+    }
+}
+
+impl<'a> Iterator for Values<'a> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|r| r.map(|(_, v)| v))
+    }
+}
@@ -1,50 +1,193 @@
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-use std::path::Path;
-
-// TODO: implement remove
-
+pub mod error;
 pub mod util;
 pub mod page;
 pub mod disk;
+pub mod iter;
+pub mod hash;
+pub mod options;
+pub mod pool;
+pub mod shadow;
+pub mod wal;
+
+use disk::{DbFile,Header,SearchResult};
+use page::{Page, SLOT_SIZE};
+pub use error::{Error, Result};
+pub use iter::{Iter, Keys, Values};
+pub use hash::{KeyHasher, Fnv1a, XxHash64, SipHash13, KeyedSipHash13};
+pub use options::{LinHashOptions, Durability};
+pub use pool::{BufferPool, PoolStats, Replacement};
 
-use disk::{DbFile,SearchResult};
+/// A (key, value) pair
+pub type Record = (Vec<u8>, Vec<u8>);
 
 /// Linear Hashtable
 pub struct LinHash {
     buckets: DbFile,
+    hasher: Box<dyn KeyHasher>,
+    threshold: f32,             // load factor that triggers a split
+    merge_threshold: f32,       // load factor that triggers a merge
+    durability: Durability,
+    unflushed: bool,            // changed since it was last flushed
+    unsynced_changes: usize,    // changes since it was last written
     nbits: usize,               // no of bits used from hash
     nitems: usize,              // number of items in hashtable
     nbuckets: usize,            // number of buckets
+    nbytes: usize,              // page space used by all records
 }
 
 impl LinHash {
-    /// "load factor" needed before the hashmap needs to grow.
-    const THRESHOLD: f32 = 0.8;
-
-    /// Creates a new Linear Hashtable.
-    pub fn open(filename: &str, keysize: usize, valsize: usize) -> LinHash {
-        let file_exists = Path::new(filename).exists();
-        let mut dbfile = DbFile::new(filename, keysize, valsize);
-        let (nbits, nitems, nbuckets) =
-            if file_exists {
-                dbfile.read_ctrlpage()
-            } else {
-                (1, 0, 2)
+    /// Opens the Linear Hashtable in `filename`, creating it if the
+    /// file does not exist or is empty. `keysize` and `valsize` are
+    /// the longest key and value expected, and a record of that size
+    /// has to fit in a page; longer ones are stored as long as the
+    /// record fits in a page. An existing table must have been
+    /// created with the same sizes, and is hashed with the built-in
+    /// hasher it was created with. New tables use `SipHash13`.
+    ///
+    /// See `LinHashOptions` for more ways to open a table.
+    pub fn open(filename: &str, keysize: usize, valsize: usize) -> Result<LinHash> {
+        LinHashOptions::new().open(filename, keysize, valsize)
+    }
+
+    /// Like `open`, but keys are hashed with `hasher`. An existing
+    /// table must have been created with the same kind of hasher; a
+    /// built-in one is seeded with the key stored in the table.
+    ///
+    /// Tables holding keys from untrusted clients should use
+    /// `KeyedSipHash13::random()`, so that nobody can pick keys that
+    /// all land in the same bucket.
+    pub fn open_with_hasher(filename: &str, keysize: usize, valsize: usize,
+                            hasher: Box<dyn KeyHasher>) -> Result<LinHash> {
+        LinHashOptions::new().hasher(hasher).open(filename, keysize, valsize)
+    }
+
+    /// Opens an existing Linear Hashtable, using the key and value
+    /// sizes, page size and built-in hasher it was created with.
+    pub fn open_existing(filename: &str) -> Result<LinHash> {
+        LinHashOptions::new().create(false).open_existing(filename)
+    }
+
+    /// Opens the table in `filename` with `options`. `sizes` holds the
+    /// key and value sizes to create the table with, or None if the
+    /// table has to exist already.
+    fn open_table(filename: &str, sizes: Option<(usize, usize)>,
+                  options: LinHashOptions) -> Result<LinHash> {
+        options.validate()?;
+        let mut dbfile = DbFile::new(filename, &options)?;
+        let LinHashOptions {
+            threshold, merge_threshold, page_size, durability, hasher, wal, shadow_paging, ..
+        } = options;
+        let merge_threshold = merge_threshold.unwrap_or(threshold / 4.0);
+
+        if dbfile.is_empty()? {
+            let (keysize, valsize) = match sizes {
+                Some(sizes) => sizes,
+                None => return Err(Error::NotATable),
             };
-        println!("{:?}", (nbits, nitems, nbuckets));
-        LinHash {
+            let page_size = dbfile.page_size();
+            LinHash::check_params(keysize, valsize, page_size)?;
+            let hasher = hasher.unwrap_or_else(|| Box::new(SipHash13));
+            dbfile.set_header(Header {
+                page_size,
+                keysize,
+                valsize,
+                hasher: hasher.id(),
+                hash_key: hasher.key(),
+                shadow_paging: shadow_paging.unwrap_or(false),
+            })?;
+            if wal {
+                dbfile.use_wal(filename)?;
+            }
+            let mut h = LinHash {
+                buckets: dbfile,
+                hasher,
+                threshold,
+                merge_threshold,
+                durability,
+                unflushed: true,
+                unsynced_changes: 0,
+                nbits: 1,
+                nitems: 0,
+                nbuckets: 2,
+                nbytes: 0,
+            };
+            h.write_ctrlpage()?;
+            return Ok(h);
+        }
+
+        let header = dbfile.read_header()?;
+        let mut requested = vec![];
+        if let Some(page_size) = page_size {
+            requested.push(("page size", page_size, header.page_size));
+        }
+        if let Some((keysize, valsize)) = sizes {
+            requested.push(("keysize", keysize, header.keysize));
+            requested.push(("valsize", valsize, header.valsize));
+        }
+        if let Some(shadow_paging) = shadow_paging {
+            requested.push(("shadow paging", shadow_paging as usize,
+                            header.shadow_paging as usize));
+        }
+        for (param, requested, stored) in requested {
+            if requested != stored {
+                return Err(Error::Mismatch { param, requested, stored });
+            }
+        }
+        if wal && header.shadow_paging {
+            return Err(Error::InvalidOption {
+                option: "wal",
+                reason: "the table uses shadow paging",
+            });
+        }
+        if wal {
+            dbfile.use_wal(filename)?;
+        }
+        LinHash::check_params(header.keysize, header.valsize, header.page_size)?;
+
+        let hasher = match hasher {
+            Some(hasher) => {
+                if hasher.id() != header.hasher {
+                    return Err(Error::Mismatch {
+                        param: "hasher",
+                        requested: hasher.id(),
+                        stored: header.hasher,
+                    });
+                }
+                hash::builtin_hasher(header.hasher, header.hash_key).unwrap_or(hasher)
+            },
+            None => hash::builtin_hasher(header.hasher, header.hash_key)
+                .ok_or(Error::UnknownHasher { id: header.hasher })?,
+        };
+        let (nbits, nitems, nbuckets, nbytes) = dbfile.read_ctrlpage()?;
+        Ok(LinHash {
             buckets: dbfile,
-            nbits: nbits,
-            nitems: nitems,
-            nbuckets: nbuckets,
+            hasher,
+            threshold,
+            merge_threshold,
+            durability,
+            unflushed: false,
+            unsynced_changes: 0,
+            nbits,
+            nitems,
+            nbuckets,
+            nbytes,
+        })
+    }
+
+    /// A record with the largest key and value has to fit in a page.
+    fn check_params(keysize: usize, valsize: usize, page_size: usize) -> Result<()> {
+        let max_len = Page::capacity(page_size) - SLOT_SIZE;
+        if keysize > max_len {
+            return Err(Error::KeyTooLarge { len: keysize, max: max_len });
+        }
+        if keysize + valsize > max_len {
+            return Err(Error::ValueTooLarge { len: valsize, max: max_len - keysize });
         }
+        Ok(())
     }
 
     fn hash(&self, key: &[u8]) -> u64 {
-        let mut s = DefaultHasher::new();
-        key.hash(&mut s);
-        s.finish()
+        self.hasher.hash(key)
     }
 
     /// Which bucket to place the key-value pair in. If the target
@@ -54,33 +197,55 @@ impl LinHash {
     fn bucket(&self, key: &[u8]) -> usize {
         let hash = self.hash(key);
         let bucket = (hash & ((1 << self.nbits) - 1)) as usize;
-        let adjusted_bucket_index =
-            if bucket < self.nbuckets {
-                bucket
-            } else {
-                bucket - (1 << (self.nbits-1))
-            };
+        if bucket < self.nbuckets {
+            bucket
+        } else {
+            bucket - (1 << (self.nbits-1))
+        }
+    }
+
+    /// A record has to fit in an empty page, so refuse larger ones up
+    /// front.
+    fn check_sizes(&self, key: &[u8], val: &[u8]) -> Result<()> {
+        let max_len = Page::capacity(self.buckets.page_size()) - SLOT_SIZE;
+        if key.len() > max_len {
+            return Err(Error::KeyTooLarge { len: key.len(), max: max_len });
+        }
+        if key.len() + val.len() > max_len {
+            return Err(Error::ValueTooLarge { len: val.len(), max: max_len - key.len() });
+        }
+        Ok(())
+    }
 
-        adjusted_bucket_index
+    /// As records vary in size, load is the fraction of bucket page
+    /// space taken up by records.
+    fn load(&self) -> f32 {
+        let capacity = Page::capacity(self.buckets.page_size());
+        self.nbytes as f32 / (capacity * self.nbuckets) as f32
     }
 
-    /// Returns true if the `load` exceeds `LinHash::THRESHOLD`
+    /// Returns true if the `load` exceeds `threshold`.
     fn split_needed(&self) -> bool {
-        (self.nitems as f32 / (self.buckets.records_per_page * self.nbuckets) as f32) >
-            LinHash::THRESHOLD
+        self.load() > self.threshold
+    }
+
+    /// Returns true if the `load` is below `merge_threshold`. A table
+    /// always keeps its first two buckets.
+    fn merge_needed(&self) -> bool {
+        self.nbuckets > 2 && self.load() < self.merge_threshold
     }
 
     /// If necessary, allocates new bucket. If there's no more space
     /// in the buckets vector(ie. n > 2^i), increment number of bits
     /// used(i).
-
+    ///
     /// Note that, the bucket split is not necessarily the one just
     /// inserted to.
-    fn maybe_split(&mut self) -> bool {
+    fn maybe_split(&mut self) -> Result<bool> {
         if self.split_needed() {
             self.nbuckets += 1;
 
-            self.buckets.allocate_new_bucket();
+            self.buckets.allocate_new_bucket()?;
             if self.nbuckets > (1 << self.nbits) {
                 self.nbits += 1;
             }
@@ -88,161 +253,1063 @@ impl LinHash {
             // Take index of last item added and subtract the 1 at the
             // MSB position. eg: after bucket 11 is added, bucket 01
             // needs to be split
-            let bucket_to_split =
-                (self.nbuckets-1) ^ (1 << (self.nbits-1));
-            println!("nbits: {} nitems: {} nbuckets: {} splitting {} and {}",
-                     self.nbits, self.nitems, self.nbuckets, bucket_to_split, (self.nbuckets-1));
-            // Replace the bucket to split with a fresh, empty
-            // page. And get a list of all records stored in the bucket
-            let old_bucket_records =
-                self.buckets.clear_bucket(bucket_to_split);
-
-            // Re-hash all records in old_bucket. Ideally, about half
-            // of the records will go into the new bucket.
-            for (k, v) in old_bucket_records.into_iter() {
-                self.reinsert(&k, &v);
-            }
-            return true
+            let new_bucket = self.nbuckets - 1;
+            let bucket_to_split = new_bucket ^ (1 << (self.nbits-1));
+
+            // Records whose hash has the MSB set belong in the new
+            // bucket. Ideally, that is about half of them.
+            let msb = 1 << (self.nbits-1);
+            let hasher = &self.hasher;
+            self.buckets.split_bucket(bucket_to_split, new_bucket,
+                                      |key| hasher.hash(key) & msb != 0)?;
+            return Ok(true)
         }
 
-        false
+        Ok(false)
     }
 
-    /// Does the hashmap contain a record with key `key`?
-    pub fn contains(&mut self, key: &[u8]) -> bool {
-        match self.get(key) {
-            Some(_) => true,
-            None => false,
+    /// If necessary, undoes the last split: the last bucket is
+    /// removed and its records are moved back to the bucket it was
+    /// split from. Its pages are added to the free list, and when the
+    /// number of buckets drops to a power of two, one bit less of the
+    /// hash is used.
+    fn maybe_merge(&mut self) -> Result<bool> {
+        if !self.merge_needed() {
+            return Ok(false);
+        }
+
+        let last_bucket = self.nbuckets - 1;
+        let buddy = last_bucket ^ (1 << (self.nbits-1));
+        let records = self.buckets.remove_last_bucket()?;
+        self.nbuckets -= 1;
+        if self.nbuckets == 1 << (self.nbits-1) {
+            self.nbits -= 1;
+        }
+
+        for (k, v) in records.into_iter() {
+            self.buckets.append_record(buddy, &k, &v)?;
         }
+        Ok(true)
+    }
+
+    /// Does the hashmap contain a record with key `key`?
+    pub fn contains(&mut self, key: &[u8]) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
     }
 
     /// Update the mapping of record with key `key`.
-    pub fn update(&mut self, key: &[u8], val: &[u8]) -> bool {
-        let bucket_index = self.bucket(&key);
-        match self.buckets.search_bucket(bucket_index, key.clone()) {
-            SearchResult { page_id, row_num, val: old_val } => {
-                match (page_id, row_num, old_val) {
-                    (Some(page_id), Some(row_num), Some(_)) => {
-                        println!("update: {:?}", (page_id, row_num, key.clone(), val.clone()));
-                        self.buckets.write_record(page_id, row_num, key, val);
-                        true
-                    }
-                    _ => false,
+    pub fn update(&mut self, key: &[u8], val: &[u8]) -> Result<bool> {
+        self.check_sizes(key, val)?;
+        let bucket_index = self.bucket(key);
+        let SearchResult { page_id, row_num, val: old_val } =
+            self.buckets.search_bucket(bucket_index, key, 0)?;
+        match (page_id, row_num, old_val) {
+            (Some(page_id), Some(row_num), Some(old_val)) => {
+                // Pages may be changed even if the update fails
+                // halfway, so they have to be flushed either way.
+                self.unflushed = true;
+                if self.buckets.write_record(page_id, row_num, key, val)? {
+                    self.nbytes = self.nbytes + val.len() - old_val.len();
+                    self.changed()?;
+                } else {
+                    // The larger value does not fit in the record's
+                    // page, so move it to wherever there is room.
+                    self.buckets.remove_record(bucket_index, page_id, row_num)?;
+                    self.nitems -= 1;
+                    self.nbytes -= Page::record_size(key.len(), old_val.len());
+                    self.put(key, val)?;
                 }
-            },
+                Ok(true)
+            }
+            _ => Ok(false),
         }
     }
 
     /// Insert (key,value) pair into the hashtable.
-    pub fn put(&mut self, key: &[u8], val: &[u8]) {
-        let bucket_index = self.bucket(&key);
-        match self.buckets.search_bucket(bucket_index, key.clone()) {
-            SearchResult { page_id, row_num, val: old_val } => {
-                match (page_id, row_num, old_val) {
-                    // new insert
-                    (Some(page_id), Some(pos), None) => {
-                        self.buckets.write_record_incr(page_id, pos, key, val);
-                        self.nitems += 1;
-                    },
-                    // case for update
-                    (Some(_page_id), Some(pos), Some(_old_val)) => {
-                        panic!("can't use put to reinsert old item: {:?}", (key, val));
-                    },
-                    // new insert, in overflow page
-                    (Some(last_page_id), None, None) => { // overflow
-                        self.buckets.allocate_overflow(bucket_index, last_page_id);
-                        self.put(key, val);
-                    },
-                    _ => panic!("impossible case"),
-                }
+    pub fn put(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.check_sizes(key, val)?;
+        let bucket_index = self.bucket(key);
+        let SearchResult { page_id, row_num, val: old_val } =
+            self.buckets.search_bucket(bucket_index, key, val.len())?;
+        match (page_id, row_num, old_val) {
+            // new insert
+            (Some(page_id), Some(_pos), None) => {
+                self.unflushed = true;
+                self.buckets.write_record_incr(page_id, key, val)?;
+                self.nitems += 1;
+                self.nbytes += Page::record_size(key.len(), val.len());
+            },
+            // case for update
+            (Some(_page_id), Some(_pos), Some(_old_val)) => {
+                return Err(Error::KeyExists);
+            },
+            // new insert, in overflow page
+            (Some(last_page_id), None, None) => { // overflow
+                self.unflushed = true;
+                self.buckets.allocate_overflow(last_page_id)?;
+                return self.put(key, val);
             },
+            // not returned by the search unless the bucket is damaged
+            _ => return Err(Error::Corrupt {
+                page_id: self.buckets.bucket_to_page(bucket_index),
+                reason: "bucket search returned no page",
+            }),
         }
 
-        self.maybe_split();
-        self.buckets.write_ctrlpage((self.nbits, self.nitems, self.nbuckets));
+        self.maybe_split()?;
+        self.changed()
     }
 
-    /// Re-insert (key, value) pair after a split
-    fn reinsert(&mut self, key: &[u8], val: &[u8]) {
-        self.put(key, val);
-        // correct for nitems increment in `put`
-        self.nitems -= 1;
+    /// Lookup `key` in hashtable
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let bucket_index = self.bucket(key);
+        Ok(self.buckets.search_bucket(bucket_index, key, 0)?.val)
     }
 
-    /// Lookup `key` in hashtable
-    pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
-        let bucket_index = self.bucket(&key);
-        match self.buckets.search_bucket(bucket_index, key) {
-            SearchResult { page_id, row_num, val } => {
-                match val {
-                    Some(v) => Some(v),
-                    _ => None,
-                }
+    /// Removes record with `key` in hashtable, returning its value if
+    /// it was present.
+    pub fn remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let bucket_index = self.bucket(key);
+        let SearchResult { page_id, row_num, val } =
+            self.buckets.search_bucket(bucket_index, key, 0)?;
+        match (page_id, row_num, val) {
+            (Some(page_id), Some(row_num), Some(val)) => {
+                self.unflushed = true;
+                self.buckets.remove_record(bucket_index, page_id, row_num)?;
+                self.nitems -= 1;
+                self.nbytes -= Page::record_size(key.len(), val.len());
+                self.maybe_merge()?;
+                self.changed()?;
+                Ok(Some(val))
             },
+            _ => Ok(None),
+        }
+    }
+
+    /// Iterate over all (key, value) pairs in the hashtable, in no
+    /// particular order.
+    pub fn iter(&mut self) -> Iter<'_> {
+        Iter::new(self)
+    }
+
+    /// Iterate over all keys in the hashtable.
+    pub fn keys(&mut self) -> Keys<'_> {
+        Keys::new(self)
+    }
+
+    /// Iterate over all values in the hashtable.
+    pub fn values(&mut self) -> Values<'_> {
+        Values::new(self)
+    }
+
+    /// Incrementally iterate over the hashtable, in the style of
+    /// Redis' SCAN. Start with a `cursor` of 0 and pass the returned
+    /// cursor to the next call; the scan is complete when it returns
+    /// 0 again. Roughly `count` records are returned per call.
+    ///
+    /// The cursor walks all `2^nbits` hash suffixes in reverse-binary
+    /// order, and each step returns the records whose hash ends in that
+    /// suffix. A split only moves records between suffixes that share
+    /// their low bits, so every key present for the whole scan is
+    /// returned at least once even if the table grows between calls.
+    /// Keys may be returned more than once.
+    pub fn scan(&mut self, cursor: u64, count: usize)
+                -> Result<(u64, Vec<Record>)> {
+        let mask: u64 = (1 << self.nbits) - 1;
+        let mut cursor = cursor;
+        let mut records = vec![];
+
+        loop {
+            let suffix = cursor & mask;
+            let bucket = if (suffix as usize) < self.nbuckets {
+                suffix as usize
+            } else {
+                (suffix as usize) - (1 << (self.nbits-1))
+            };
+            for (k, v) in self.buckets.bucket_records(bucket)? {
+                if self.hash(&k) & mask == suffix {
+                    records.push((k, v));
+                }
+            }
+
+            // Increment the reversed cursor
+            cursor |= !mask;
+            cursor = cursor.reverse_bits().wrapping_add(1).reverse_bits();
+
+            if cursor == 0 || records.len() >= count {
+                return Ok((cursor, records));
+            }
         }
     }
 
-    // Removes record with `key` in hashtable.
-    // pub fn remove(&mut self, key: K) -> Option<V> {
-    //     let bucket_index = self.bucket(&key);
-    //     let index_to_delete = self.search_bucket(bucket_index, &key);
+    /// Number of page lookups in the buffer pool that found the page
+    /// there, and that had to read it from the file.
+    pub fn pool_stats(&self) -> PoolStats {
+        self.buckets.pool_stats()
+    }
+
+    /// Bytes of pages kept in memory, by all tables sharing the
+    /// buffer pool.
+    pub fn cache_capacity(&self) -> usize {
+        self.buckets.cache_capacity()
+    }
+
+    /// Grows or shrinks the pages kept in memory to `capacity` bytes.
+    /// When shrinking, changed pages that no longer fit are written
+    /// to the file. A shared buffer pool is resized for every table
+    /// using it.
+    pub fn set_cache_capacity(&mut self, capacity: usize) -> Result<()> {
+        self.buckets.set_cache_capacity(capacity)
+    }
 
-    //     // Delete item from bucket
-    //     match index_to_delete {
-    //         Some(x) => Some(self.buckets[bucket_index].remove(x).1),
-    //         None => None,
-    //     }
-    // }
+    /// Called after each change to the table. The control page is
+    /// only written out when the durability policy asks for it.
+    fn changed(&mut self) -> Result<()> {
+        self.unflushed = true;
+        self.unsynced_changes += 1;
+        match self.durability {
+            Durability::Every(n) if self.unsynced_changes >= n => self.write_ctrlpage(),
+            // Evicted pages keep being appended to a write-ahead log
+            // until the next commit lets it be emptied
+            _ if self.buckets.log_full() => self.write_ctrlpage(),
+            _ => Ok(()),
+        }
+    }
 
-    pub fn close(&mut self) {
-        self.buckets.write_ctrlpage((self.nbits, self.nitems, self.nbuckets));
-        self.buckets.close();
+    fn write_ctrlpage(&mut self) -> Result<()> {
+        self.buckets.write_ctrlpage((self.nbits, self.nitems, self.nbuckets, self.nbytes))?;
+        self.unsynced_changes = 0;
+        Ok(())
+    }
+
+    /// Writes every changed page and the control page to the file,
+    /// and waits until they are on disk. The table stays open. Does
+    /// nothing if the table was not changed since the last flush.
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.unflushed {
+            return Ok(());
+        }
+        self.buckets.flush((self.nbits, self.nitems, self.nbuckets, self.nbytes))?;
+        self.unflushed = false;
+        self.unsynced_changes = 0;
+        Ok(())
+    }
+
+    /// Flushes the table. Dropping it does the same, but errors are
+    /// only seen through `close`.
+    pub fn close(&mut self) -> Result<()> {
+        self.flush()
+    }
+}
+
+impl Drop for LinHash {
+    fn drop(&mut self) {
+        let _ = self.flush();
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use LinHash;
+    use {BufferPool, Durability, Error, LinHash, LinHashOptions, Replacement, KeyHasher, Fnv1a, XxHash64, KeyedSipHash13};
+    use page::{self, Page};
+    use std::collections::HashSet;
     use std::fs;
+    use std::io::Write;
+    use std::mem;
+    use std::sync::Arc;
     use util::*;
+    use wal;
 
     #[test]
     fn all_ops() {
-        let mut h = LinHash::open("/tmp/test_all_ops", 32, 4);
-        h.put(b"hello", &[12]);
-        h.put(b"there", &[13]);
-        h.put(b"foo", &[42]);
-        h.put(b"bar", &[11]);
-        h.update(b"bar", &[22]);
-        h.update(b"foo", &[84]);
-
-        assert_eq!(h.get(b"hello"), Some(vec![12, 0, 0, 0]));
-        assert_eq!(h.get(b"there"), Some(vec![13, 0, 0, 0]));
-        assert_eq!(h.get(b"foo"), Some(vec![84, 0, 0, 0]));
-        assert_eq!(h.get(b"bar"), Some(vec![22, 0, 0, 0]));
+        let mut h = LinHash::open("/tmp/test_all_ops", 32, 4).unwrap();
+        h.put(b"hello", &[12]).unwrap();
+        h.put(b"there", &[13]).unwrap();
+        h.put(b"foo", &[42]).unwrap();
+        h.put(b"bar", &[11]).unwrap();
+        h.update(b"bar", &[22]).unwrap();
+        h.update(b"foo", &[84]).unwrap();
+
+        assert_eq!(h.get(b"hello").unwrap(), Some(vec![12]));
+        assert_eq!(h.get(b"there").unwrap(), Some(vec![13]));
+        assert_eq!(h.get(b"foo").unwrap(), Some(vec![84]));
+        assert_eq!(h.get(b"bar").unwrap(), Some(vec![22]));
 
         // assert_eq!(h.update(String::from("doesn't exist"), 99), false);
-        assert_eq!(h.contains(b"doesn't exist"), false);
-        assert_eq!(h.contains(b"hello"), true);
+        assert!(!h.contains(b"doesn't exist").unwrap());
+        assert!(h.contains(b"hello").unwrap());
 
-        h.close();
+        h.close().unwrap();
         fs::remove_file("/tmp/test_all_ops").ok();
     }
 
+    #[test]
+    fn test_errors() {
+        fs::remove_file("/tmp/test_errors").ok();
+        let mut h = LinHash::open("/tmp/test_errors", 4, 4).unwrap();
+        h.put(b"key", b"val").unwrap();
+        match h.put(b"key", b"val2") {
+            Err(Error::KeyExists) => (),
+            other => panic!("expected KeyExists, got {:?}", other),
+        }
+        match h.put(&[1; 5000], b"val") {
+            Err(Error::KeyTooLarge { len: 5000, max: 4058 }) => (),
+            other => panic!("expected KeyTooLarge, got {:?}", other),
+        }
+        match h.update(b"key", &[1; 4056]) {
+            Err(Error::ValueTooLarge { len: 4056, max: 4055 }) => (),
+            other => panic!("expected ValueTooLarge, got {:?}", other),
+        }
+
+        // Records longer than the sizes the table was opened with are
+        // fine as long as they fit in a page
+        h.put(b"a longer key", b"val").unwrap();
+        assert!(h.update(b"key", &[2; 4055]).unwrap());
+        assert_eq!(h.get(b"key").unwrap(), Some(vec![2; 4055]));
+        assert_eq!(h.get(b"a longer key").unwrap(), Some(b"val".to_vec()));
+        h.close().unwrap();
+
+        assert!(LinHash::open("/tmp/no/such/dir/test_errors", 4, 4).is_err());
+        fs::remove_file("/tmp/test_errors").ok();
+    }
+
+    #[test]
+    fn test_exact_keys() {
+        fs::remove_file("/tmp/test_exact_keys").ok();
+        let mut h = LinHash::open("/tmp/test_exact_keys", 32, 32).unwrap();
+        h.put(b"foobar", b"long").unwrap();
+        assert_eq!(h.get(b"foo").unwrap(), None);
+        assert_eq!(h.get(b"").unwrap(), None);
+        assert!(!h.update(b"foo", b"x").unwrap());
+        assert_eq!(h.remove(b"foo").unwrap(), None);
+
+        h.put(b"foo", b"short").unwrap();
+        h.put(b"foo\0", b"nul").unwrap();
+        h.put(b"", b"").unwrap();
+        assert_eq!(h.get(b"foo").unwrap(), Some(b"short".to_vec()));
+        assert_eq!(h.get(b"foobar").unwrap(), Some(b"long".to_vec()));
+        assert_eq!(h.get(b"foo\0").unwrap(), Some(b"nul".to_vec()));
+        assert_eq!(h.get(b"").unwrap(), Some(vec![]));
+
+        assert_eq!(h.remove(b"foo").unwrap(), Some(b"short".to_vec()));
+        assert_eq!(h.get(b"foobar").unwrap(), Some(b"long".to_vec()));
+        h.close().unwrap();
+        fs::remove_file("/tmp/test_exact_keys").ok();
+    }
+
+    #[test]
+    fn test_header() {
+        fs::remove_file("/tmp/test_header").ok();
+        let mut h = LinHash::open("/tmp/test_header", 4, 8).unwrap();
+        h.put(b"key", b"value").unwrap();
+        h.close().unwrap();
+
+        match LinHash::open("/tmp/test_header", 32, 8) {
+            Err(Error::Mismatch { param: "keysize", requested: 32, stored: 4 }) => (),
+            other => panic!("expected keysize mismatch, got {:?}", other.err()),
+        }
+        match LinHash::open("/tmp/test_header", 4, 4) {
+            Err(Error::Mismatch { param: "valsize", requested: 4, stored: 8 }) => (),
+            other => panic!("expected valsize mismatch, got {:?}", other.err()),
+        }
+
+        let mut h2 = LinHash::open_existing("/tmp/test_header").unwrap();
+        assert_eq!(h2.get(b"key").unwrap(), Some(b"value".to_vec()));
+        h2.close().unwrap();
+
+        // An empty file is a new table for `open`, but not a table
+        // for `open_existing`
+        fs::write("/tmp/test_header", b"").unwrap();
+        match LinHash::open_existing("/tmp/test_header") {
+            Err(Error::NotATable) => (),
+            other => panic!("expected NotATable, got {:?}", other.err()),
+        }
+        let mut h3 = LinHash::open("/tmp/test_header", 4, 8).unwrap();
+        assert_eq!(h3.get(b"key").unwrap(), None);
+        h3.close().unwrap();
+
+        fs::write("/tmp/test_header", &[0xab; 8192][..]).unwrap();
+        match LinHash::open("/tmp/test_header", 4, 8) {
+            Err(Error::NotATable) => (),
+            other => panic!("expected NotATable, got {:?}", other.err()),
+        }
+
+        fs::remove_file("/tmp/test_header").ok();
+        match LinHash::open_existing("/tmp/test_header") {
+            Err(Error::Io(_)) => (),
+            other => panic!("expected Io error, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_ctrl_slots() {
+        let path = "/tmp/test_ctrl_slots";
+        fs::remove_file(path).ok();
+        let mut h = LinHashOptions::new()
+            .durability(Durability::Every(1))
+            .open(path, 4, 4).unwrap();
+        for k in 0..10 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k)).unwrap();
+        }
+        h.close().unwrap();
+        drop(h);
+
+        // Tear the newest slot; the other one is used instead
+        let mut page = fs::read(path).unwrap();
+        let generation = |page: &[u8], slot: usize| {
+            let offset = slot * 256 + 18 * 8;
+            bytearray_to_usize(page[offset..offset+8].to_vec())
+        };
+        let newest = if generation(&page, 0) > generation(&page, 1) { 0 } else { 1 };
+        assert_eq!(generation(&page, newest), generation(&page, 1 - newest) + 1);
+        page[newest * 256 + 7 * 8] ^= 0xff;
+        fs::write(path, &page).unwrap();
+        let mut h = LinHash::open_existing(path).unwrap();
+        assert_eq!(h.nitems, 10);
+        for k in 0..10 {
+            assert_eq!(h.get(&i32_to_bytearray(k)).unwrap(), Some(i32_to_bytearray(k).to_vec()));
+        }
+
+        // The next control page goes to the torn slot, and nothing
+        // else of page 0 is written
+        page[2048] = 0xaa;
+        fs::write(path, &page).unwrap();
+        h.put(&i32_to_bytearray(10), &i32_to_bytearray(10)).unwrap();
+        h.close().unwrap();
+        drop(h);
+        let mut page = fs::read(path).unwrap();
+        let offset = newest * 256 + 7 * 8;
+        assert_eq!(bytearray_to_usize(page[offset..offset+8].to_vec()), 11);
+        assert_eq!(page[2048], 0xaa);
+
+        // With both slots torn the table cannot be opened
+        page[7 * 8] ^= 0xff;
+        page[256 + 7 * 8] ^= 0xff;
+        fs::write(path, &page).unwrap();
+        match LinHash::open_existing(path) {
+            Err(Error::Corrupt { page_id: 0, .. }) => (),
+            other => panic!("expected Corrupt, got {:?}", other.err()),
+        }
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_page_checksums() {
+        let path = "/tmp/test_page_checksums";
+        fs::remove_file(path).ok();
+        let mut h = LinHash::open(path, 4, 4).unwrap();
+        for k in 0..10 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k)).unwrap();
+        }
+        h.close().unwrap();
+        drop(h);
+
+        // Damage the last row of the first bucket page
+        let mut file = fs::read(path).unwrap();
+        file[2 * page::DEFAULT_PAGE_SIZE - 1] ^= 0xff;
+        fs::write(path, &file).unwrap();
+
+        let mut h = LinHash::open_existing(path).unwrap();
+        let corrupt = (0..10).filter(|&k| match h.get(&i32_to_bytearray(k)) {
+            Err(Error::Corrupt { page_id: 1, .. }) => true,
+            Ok(_) => false,
+            Err(e) => panic!("expected Corrupt, got {:?}", e),
+        }).count();
+        assert!(corrupt > 0);
+        drop(h);
+
+        // Without verification, the damaged page is read as it is
+        let mut h = LinHashOptions::new().verify_checksums(false).open_existing(path).unwrap();
+        for k in 0..10 {
+            h.get(&i32_to_bytearray(k)).unwrap();
+        }
+        drop(h);
+
+        // A page found where another one belongs, or zeroed out, does
+        // not pass either
+        let page_size = page::DEFAULT_PAGE_SIZE;
+        for &zeroed in &[false, true] {
+            let (first, second) = file.split_at_mut(2 * page_size);
+            if zeroed {
+                first[page_size..].iter_mut().for_each(|b| *b = 0);
+            } else {
+                first[page_size..].copy_from_slice(&second[..page_size]);
+            }
+            fs::write(path, &file).unwrap();
+            let mut h = LinHash::open_existing(path).unwrap();
+            assert!((0..10).any(|k| matches!(h.get(&i32_to_bytearray(k)),
+                                             Err(Error::Corrupt { page_id: 1, .. }))));
+        }
+        fs::remove_file(path).ok();
+    }
+
+    struct FirstByte;
+
+    impl KeyHasher for FirstByte {
+        fn id(&self) -> usize {
+            1000
+        }
+
+        fn hash(&self, key: &[u8]) -> u64 {
+            key.first().cloned().unwrap_or(0) as u64
+        }
+    }
+
+    #[test]
+    fn test_hasher() {
+        fs::remove_file("/tmp/test_hasher").ok();
+        let mut h = LinHash::open_with_hasher("/tmp/test_hasher", 8, 8,
+                                              Box::new(Fnv1a)).unwrap();
+        for i in 0..1000u32 {
+            h.put(&i.to_ne_bytes(), &i.to_ne_bytes()).unwrap();
+        }
+        h.close().unwrap();
+
+        // `open` picks up the hasher the table was created with
+        let mut h2 = LinHash::open("/tmp/test_hasher", 8, 8).unwrap();
+        for i in 0..1000u32 {
+            assert_eq!(h2.get(&i.to_ne_bytes()).unwrap(), Some(i.to_ne_bytes().to_vec()));
+        }
+        h2.close().unwrap();
+
+        match LinHash::open_with_hasher("/tmp/test_hasher", 8, 8, Box::new(XxHash64)) {
+            Err(Error::Mismatch { param: "hasher", requested: 2, stored: 1 }) => (),
+            other => panic!("expected hasher mismatch, got {:?}", other.err()),
+        }
+
+        // A random key is kept in the table, so a fresh random hasher
+        // still finds the records
+        fs::remove_file("/tmp/test_hasher").ok();
+        let hasher = KeyedSipHash13::random();
+        let key = hasher.key();
+        let mut h = LinHash::open_with_hasher("/tmp/test_hasher", 8, 8,
+                                              Box::new(hasher)).unwrap();
+        for i in 0..1000u32 {
+            h.put(&i.to_ne_bytes(), &i.to_ne_bytes()).unwrap();
+        }
+        h.close().unwrap();
+        let mut h2 = LinHash::open_with_hasher("/tmp/test_hasher", 8, 8,
+                                               Box::new(KeyedSipHash13::random())).unwrap();
+        assert_eq!(h2.hasher.key(), key);
+        for i in 0..1000u32 {
+            assert_eq!(h2.get(&i.to_ne_bytes()).unwrap(), Some(i.to_ne_bytes().to_vec()));
+        }
+        h2.close().unwrap();
+        let mut h2 = LinHash::open_existing("/tmp/test_hasher").unwrap();
+        assert_eq!(h2.get(&7u32.to_ne_bytes()).unwrap(), Some(7u32.to_ne_bytes().to_vec()));
+        h2.close().unwrap();
+
+        // A custom hasher has to be passed in every time
+        fs::remove_file("/tmp/test_hasher").ok();
+        let mut h3 = LinHash::open_with_hasher("/tmp/test_hasher", 8, 8,
+                                               Box::new(FirstByte)).unwrap();
+        h3.put(b"custom", b"hasher").unwrap();
+        h3.close().unwrap();
+        match LinHash::open_existing("/tmp/test_hasher") {
+            Err(Error::UnknownHasher { id: 1000 }) => (),
+            other => panic!("expected unknown hasher, got {:?}", other.err()),
+        }
+        let mut h4 = LinHash::open_with_hasher("/tmp/test_hasher", 8, 8,
+                                               Box::new(FirstByte)).unwrap();
+        assert_eq!(h4.get(b"custom").unwrap(), Some(b"hasher".to_vec()));
+        h4.close().unwrap();
+        fs::remove_file("/tmp/test_hasher").ok();
+    }
+
+    #[test]
+    fn test_options() {
+        fs::remove_file("/tmp/test_options").ok();
+        let invalid = vec![
+            LinHashOptions::new().threshold(0.0),
+            LinHashOptions::new().page_size(1000),
+            LinHashOptions::new().page_size(65536),
+            LinHashOptions::new().threshold(0.5).merge_threshold(0.3),
+        ];
+        for options in invalid {
+            match options.open("/tmp/test_options", 4, 4) {
+                Err(Error::InvalidOption { .. }) => (),
+                other => panic!("expected invalid option, got {:?}", other.err()),
+            }
+        }
+        match LinHashOptions::new().page_size(512).open("/tmp/test_options", 4, 500) {
+            Err(Error::ValueTooLarge { len: 500, max: 470 }) => (),
+            other => panic!("expected ValueTooLarge, got {:?}", other.err()),
+        }
+        match LinHashOptions::new().create(false).open("/tmp/no_such_table", 4, 4) {
+            Err(Error::Io(_)) => (),
+            other => panic!("expected Io error, got {:?}", other.err()),
+        }
+
+        // Small pages and a tiny cache still hold everything
+        fs::remove_file("/tmp/test_options").ok();
+        let mut h = LinHashOptions::new()
+            .page_size(1024)
+            .cache_capacity(0)
+            .threshold(0.5)
+            .open("/tmp/test_options", 4, 16).unwrap();
+        for i in 0..2000u32 {
+            h.put(&i.to_ne_bytes(), &[i as u8; 16]).unwrap();
+        }
+        // Every bucket was split at half load
+        assert!(h.nbuckets * Page::capacity(1024) >= 2 * h.nbytes);
+        h.close().unwrap();
+
+        // The page size is kept in the file
+        match LinHashOptions::new().page_size(4096).open("/tmp/test_options", 4, 16) {
+            Err(Error::Mismatch { param: "page size", requested: 4096, stored: 1024 }) => (),
+            other => panic!("expected page size mismatch, got {:?}", other.err()),
+        }
+        let mut h2 = LinHash::open("/tmp/test_options", 4, 16).unwrap();
+        assert_eq!(h2.buckets.page_size(), 1024);
+        for i in 0..2000u32 {
+            assert_eq!(h2.get(&i.to_ne_bytes()).unwrap(), Some(vec![i as u8; 16]));
+        }
+        h2.close().unwrap();
+
+        let mut h3 = LinHashOptions::new().truncate(true)
+            .open("/tmp/test_options", 8, 8).unwrap();
+        assert_eq!(h3.buckets.page_size(), page::DEFAULT_PAGE_SIZE);
+        assert_eq!(h3.iter().count(), 0);
+        h3.close().unwrap();
+        fs::remove_file("/tmp/test_options").ok();
+    }
+
+    #[test]
+    fn test_merge() {
+        fs::remove_file("/tmp/test_merge").ok();
+        // Small pages, so that the directory takes several pages
+        let options = || LinHashOptions::new().page_size(512);
+        let mut h = options().open("/tmp/test_merge", 4, 4).unwrap();
+        for k in 0..20000 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k+1)).unwrap();
+        }
+        let (nbuckets, nbits) = (h.nbuckets, h.nbits);
+        assert!(nbuckets > 500);
+        for k in 100..20000 {
+            h.remove(&i32_to_bytearray(k)).unwrap();
+        }
+        assert!(h.nbuckets < nbuckets / 10);
+        assert!(h.nbits < nbits);
+        assert!(h.nbuckets > 1 << (h.nbits-1) && h.nbuckets <= 1 << h.nbits);
+        h.close().unwrap();
+        let file_len = fs::metadata("/tmp/test_merge").unwrap().len();
+
+        let mut h2 = options().open("/tmp/test_merge", 4, 4).unwrap();
+        for k in 0..20000 {
+            let expected = if k < 100 { Some(i32_to_bytearray(k+1).to_vec()) } else { None };
+            assert_eq!(h2.get(&i32_to_bytearray(k)).unwrap(), expected);
+        }
+
+        // Growing again reuses the freed pages. Splits do not happen
+        // in the same order, so a few more overflow pages may be
+        // needed than the first time.
+        for k in 100..20000 {
+            h2.put(&i32_to_bytearray(k), &i32_to_bytearray(k+1)).unwrap();
+        }
+        assert_eq!(h2.iter().count(), 20000);
+        h2.close().unwrap();
+        assert!(fs::metadata("/tmp/test_merge").unwrap().len() < file_len + file_len / 10);
+
+        let h3 = options().open("/tmp/test_merge", 4, 4).unwrap();
+        assert_eq!(h3.nbuckets, nbuckets);
+        fs::remove_file("/tmp/test_merge").ok();
+    }
+
+    #[test]
+    fn test_merge_then_split() {
+        fs::remove_file("/tmp/test_merge_then_split").ok();
+        let mut h = LinHash::open("/tmp/test_merge_then_split", 4, 4).unwrap();
+        for k in 0..20000 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k+1)).unwrap();
+        }
+        h.flush().unwrap();
+
+        // Buckets are dropped and split again before the directory is
+        // written out
+        for k in 100..20000 {
+            h.remove(&i32_to_bytearray(k)).unwrap();
+        }
+        for k in 100..20000 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k+1)).unwrap();
+        }
+        h.close().unwrap();
+        drop(h);
+
+        let mut h = LinHash::open_existing("/tmp/test_merge_then_split").unwrap();
+        for k in 0..20000 {
+            assert_eq!(h.get(&i32_to_bytearray(k)).unwrap(), Some(i32_to_bytearray(k+1).to_vec()));
+        }
+        fs::remove_file("/tmp/test_merge_then_split").ok();
+    }
+
+    #[test]
+    fn test_durability() {
+        fs::remove_file("/tmp/test_durability").ok();
+        let ctrl_page = || fs::read("/tmp/test_durability").unwrap()[..4096].to_vec();
+
+        let mut h = LinHash::open("/tmp/test_durability", 4, 4).unwrap();
+        let created = ctrl_page();
+        for k in 0..100 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k)).unwrap();
+        }
+        assert!(ctrl_page() == created);
+        h.close().unwrap();
+        assert!(ctrl_page() != created);
+
+        let mut h = LinHashOptions::new().durability(Durability::Every(10))
+            .open("/tmp/test_durability", 4, 4).unwrap();
+        let mut written = ctrl_page();
+        for k in 100..200 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k)).unwrap();
+            assert_eq!(ctrl_page() != written, (k + 1) % 10 == 0);
+            written = ctrl_page();
+        }
+        h.close().unwrap();
+
+        let mut h = LinHash::open("/tmp/test_durability", 4, 4).unwrap();
+        assert_eq!(h.iter().count(), 200);
+        h.close().unwrap();
+        fs::remove_file("/tmp/test_durability").ok();
+    }
+
+    #[test]
+    fn test_flush() {
+        fs::remove_file("/tmp/test_flush").ok();
+        let mut h = LinHash::open("/tmp/test_flush", 4, 4).unwrap();
+        for k in 0..1000 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k)).unwrap();
+        }
+        h.flush().unwrap();
+
+        // A second handle sees everything written before the flush,
+        // and only reading the table leaves the file as it was
+        let file = fs::read("/tmp/test_flush").unwrap();
+        let mut h2 = LinHash::open_existing("/tmp/test_flush").unwrap();
+        assert_eq!(h2.iter().count(), 1000);
+        h2.close().unwrap();
+        drop(h2);
+        h.flush().unwrap();
+        assert!(fs::read("/tmp/test_flush").unwrap() == file);
+
+        // Dropping the table without closing it keeps the changes
+        for k in 1000..2000 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k)).unwrap();
+        }
+        drop(h);
+        let mut h = LinHash::open_existing("/tmp/test_flush").unwrap();
+        assert_eq!(h.iter().count(), 2000);
+        h.close().unwrap();
+        fs::remove_file("/tmp/test_flush").ok();
+    }
+
+    #[test]
+    fn test_crash_in_place() {
+        let path = "/tmp/test_crash_in_place";
+        fs::remove_file(path).ok();
+
+        // Crash, leaving the buffer pool unwritten, after every put
+        // had the control page written
+        let mut h = LinHashOptions::new()
+            .durability(Durability::Every(1))
+            .page_size(512)
+            .cache_capacity(4 * 512)
+            .open(path, 4, 4).unwrap();
+        for k in 0..500 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k)).unwrap();
+        }
+        mem::forget(h);
+
+        let mut h = LinHash::open_existing(path).unwrap();
+        assert_eq!(h.iter().count(), 500);
+        for k in 0..500 {
+            assert_eq!(h.get(&i32_to_bytearray(k)).unwrap(), Some(i32_to_bytearray(k).to_vec()));
+        }
+        h.close().unwrap();
+        drop(h);
+
+        // Crash with only evicted pages written. The file holds pages
+        // past those the control page knows of, which are allocated
+        // as fresh ones.
+        fs::remove_file(path).ok();
+        let options = || LinHashOptions::new().page_size(512).cache_capacity(4 * 512);
+        let mut h = options().open(path, 4, 4).unwrap();
+        for k in 0..5000 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k)).unwrap();
+        }
+        mem::forget(h);
+        let mut h = options().open_existing(path).unwrap();
+        let before = h.iter().count();
+        for k in 5000..10000 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k)).unwrap();
+        }
+        h.close().unwrap();
+        drop(h);
+        let mut h = LinHash::open_existing(path).unwrap();
+        assert_eq!(h.iter().count(), before + 5000);
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_wal() {
+        let (path, wal_path) = ("/tmp/test_wal", "/tmp/test_wal-wal");
+        fs::remove_file(path).ok();
+        fs::remove_file(wal_path).ok();
+        let options = || LinHashOptions::new().wal(true).cache_capacity(4 * 4096);
+
+        // Crash, leaving the buffer pool unwritten, after every put
+        // was committed
+        let mut h = options().durability(Durability::Every(1)).open(path, 4, 4).unwrap();
+        for k in 0..500 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k)).unwrap();
+        }
+        mem::forget(h);
+        assert_eq!(fs::metadata(path).unwrap().len(), 0);
+        let mut h = options().open_existing(path).unwrap();
+        assert_eq!(h.iter().count(), 500);
+
+        // Changes since the last commit are lost, even those already
+        // written back to the log, but the table is intact
+        for k in 500..1000 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k)).unwrap();
+        }
+        mem::forget(h);
+        let mut h = options().durability(Durability::Every(1)).open_existing(path).unwrap();
+        assert_eq!(h.iter().count(), 500);
+        for k in 0..500 {
+            assert_eq!(h.get(&i32_to_bytearray(k)).unwrap(), Some(i32_to_bytearray(k).to_vec()));
+        }
+
+        // A torn record at the end of the log is ignored
+        h.put(&i32_to_bytearray(500), &i32_to_bytearray(500)).unwrap();
+        mem::forget(h);
+        let mut wal = fs::OpenOptions::new().append(true).open(wal_path).unwrap();
+        wal.write_all(&[1, 0, 0, 0, 0, 0, 0, 0, 7]).unwrap();
+        drop(wal);
+        let mut h = options().open_existing(path).unwrap();
+        assert_eq!(h.iter().count(), 501);
+
+        // So are records with lengths no page has
+        for &len in &[usize::MAX - 8, 1 << 40, 4096 + 1] {
+            h.put(&i32_to_bytearray(501), &i32_to_bytearray(501)).unwrap();
+            h.remove(&i32_to_bytearray(501)).unwrap();
+            mem::forget(h);
+            let mut wal = fs::OpenOptions::new().append(true).open(wal_path).unwrap();
+            for &field in &[1, 5, len] {
+                wal.write_all(&usize_to_bytearray(field)).unwrap();
+            }
+            drop(wal);
+            h = options().open_existing(path).unwrap();
+            assert_eq!(h.iter().count(), 501);
+        }
+
+        // The log is committed and checkpointed once it passes its
+        // bound, even with nothing asking for commits
+        for k in 1000..20000 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k)).unwrap();
+            assert!(fs::metadata(wal_path).unwrap().len() < wal::CHECKPOINT_LEN + 64 * 4096);
+        }
+
+        // Closing copies the log into the table and empties it
+        h.close().unwrap();
+        assert_eq!(fs::metadata(wal_path).unwrap().len(), 0);
+        drop(h);
+        let mut h = LinHash::open_existing(path).unwrap();
+        assert_eq!(h.iter().count(), 19501);
+        h.close().unwrap();
+        fs::remove_file(path).ok();
+        fs::remove_file(wal_path).ok();
+    }
+
+    #[test]
+    fn test_shadow_paging() {
+        let path = "/tmp/test_shadow_paging";
+        fs::remove_file(path).ok();
+        let options = || LinHashOptions::new().shadow_paging(true).cache_capacity(4 * 4096);
+
+        // Crash after every put was committed
+        let mut h = options().durability(Durability::Every(1)).open(path, 4, 4).unwrap();
+        for k in 0..500 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k)).unwrap();
+        }
+        mem::forget(h);
+        let mut h = LinHash::open_existing(path).unwrap();
+        assert_eq!(h.iter().count(), 500);
+
+        // Changes since the last commit are lost, even those already
+        // written back, but the table is intact
+        for k in 500..1000 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k)).unwrap();
+        }
+        mem::forget(h);
+        let mut h = options().open_existing(path).unwrap();
+        assert_eq!(h.iter().count(), 500);
+        for k in 0..500 {
+            assert_eq!(h.get(&i32_to_bytearray(k)).unwrap(), Some(i32_to_bytearray(k).to_vec()));
+        }
+        assert!(fs::metadata(format!("{}-wal", path)).is_err());
+
+        // Pages of old versions are reused
+        h.close().unwrap();
+        let len = fs::metadata(path).unwrap().len();
+        for k in 0..500 {
+            assert!(h.update(&i32_to_bytearray(k), &i32_to_bytearray(k + 1)).unwrap());
+            h.flush().unwrap();
+        }
+        drop(h);
+        assert!(fs::metadata(path).unwrap().len() <= 2 * len);
+        let mut h = LinHash::open_existing(path).unwrap();
+        assert_eq!(h.get(&i32_to_bytearray(7)).unwrap(), Some(i32_to_bytearray(8).to_vec()));
+
+        match LinHashOptions::new().shadow_paging(false).open_existing(path) {
+            Err(Error::Mismatch { param: "shadow paging", requested: 0, stored: 1 }) => (),
+            _ => panic!("expected a shadow paging mismatch"),
+        }
+        match LinHashOptions::new().wal(true).open_existing(path) {
+            Err(Error::InvalidOption { option: "wal", .. }) => (),
+            _ => panic!("expected wal to be rejected"),
+        }
+        assert!(fs::metadata(format!("{}-wal", path)).is_err());
+        h.close().unwrap();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_replacement() {
+        for &replacement in &[Replacement::Lru, Replacement::Clock, Replacement::TwoQ] {
+            fs::remove_file("/tmp/test_replacement").ok();
+            let mut h = LinHashOptions::new()
+                .replacement(replacement)
+                .cache_capacity(4 * 4096)
+                .open("/tmp/test_replacement", 4, 4).unwrap();
+            for k in 0..3000 {
+                h.put(&i32_to_bytearray(k), &i32_to_bytearray(k+1)).unwrap();
+            }
+            for k in 0..3000 {
+                assert_eq!(h.get(&i32_to_bytearray(k)).unwrap(),
+                           Some(i32_to_bytearray(k+1).to_vec()));
+            }
+
+            // The same bucket over and over is always a hit, once it
+            // was read in
+            h.get(&i32_to_bytearray(7)).unwrap();
+            let before = h.pool_stats();
+            for _ in 0..10 {
+                h.get(&i32_to_bytearray(7)).unwrap();
+            }
+            let after = h.pool_stats();
+            assert_eq!(after.misses, before.misses);
+            assert!(after.hits >= before.hits + 10);
+            h.close().unwrap();
+
+            let mut h2 = LinHash::open("/tmp/test_replacement", 4, 4).unwrap();
+            assert_eq!(h2.iter().count(), 3000);
+            h2.close().unwrap();
+        }
+        fs::remove_file("/tmp/test_replacement").ok();
+    }
+
+    #[test]
+    fn test_cache_capacity() {
+        fs::remove_file("/tmp/test_cache_capacity").ok();
+        let mut h = LinHashOptions::new()
+            .cache_capacity(4 * 4096)
+            .open("/tmp/test_cache_capacity", 4, 4).unwrap();
+        assert_eq!(h.cache_capacity(), 4 * 4096);
+        for k in 0..3000 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k+1)).unwrap();
+        }
+
+        // Once the whole table fits, reading it again never misses
+        h.set_cache_capacity(1 << 20).unwrap();
+        assert_eq!(h.cache_capacity(), 1 << 20);
+        for k in 0..3000 {
+            h.get(&i32_to_bytearray(k)).unwrap();
+        }
+        let before = h.pool_stats();
+        for k in 0..3000 {
+            h.get(&i32_to_bytearray(k)).unwrap();
+        }
+        assert_eq!(h.pool_stats().misses, before.misses);
+
+        // Shrinking writes back the changed pages it drops
+        for k in 0..3000 {
+            h.update(&i32_to_bytearray(k), &i32_to_bytearray(k+2)).unwrap();
+        }
+        h.set_cache_capacity(2 * 4096).unwrap();
+        for k in 0..3000 {
+            assert_eq!(h.get(&i32_to_bytearray(k)).unwrap(),
+                       Some(i32_to_bytearray(k+2).to_vec()));
+        }
+        h.close().unwrap();
+
+        let mut h2 = LinHash::open("/tmp/test_cache_capacity", 4, 4).unwrap();
+        for k in 0..3000 {
+            assert_eq!(h2.get(&i32_to_bytearray(k)).unwrap(),
+                       Some(i32_to_bytearray(k+2).to_vec()));
+        }
+        h2.close().unwrap();
+        fs::remove_file("/tmp/test_cache_capacity").ok();
+    }
+
+    #[test]
+    fn test_shared_pool() {
+        fs::remove_file("/tmp/test_shared_pool_a").ok();
+        fs::remove_file("/tmp/test_shared_pool_b").ok();
+        let pool = Arc::new(BufferPool::new(1024, 8 * 1024, Replacement::Lru).unwrap());
+        let options = || LinHashOptions::new().buffer_pool(pool.clone());
+        let mut a = options().open("/tmp/test_shared_pool_a", 4, 4).unwrap();
+        let mut b = options().open("/tmp/test_shared_pool_b", 4, 4).unwrap();
+        assert_eq!(a.buckets.page_size(), 1024);
+        for k in 0..2000 {
+            a.put(&i32_to_bytearray(k), &i32_to_bytearray(k+1)).unwrap();
+            b.put(&i32_to_bytearray(k), &i32_to_bytearray(k+2)).unwrap();
+        }
+        for k in 0..2000 {
+            assert_eq!(a.get(&i32_to_bytearray(k)).unwrap(), Some(i32_to_bytearray(k+1).to_vec()));
+            assert_eq!(b.get(&i32_to_bytearray(k)).unwrap(), Some(i32_to_bytearray(k+2).to_vec()));
+        }
+        // One budget and one set of counters for both
+        assert_eq!(a.pool_stats(), b.pool_stats());
+        a.set_cache_capacity(4 * 1024).unwrap();
+        assert_eq!(b.cache_capacity(), 4 * 1024);
+        a.close().unwrap();
+        b.close().unwrap();
+
+        // Tables with other page sizes cannot use the pool
+        match options().page_size(4096).open("/tmp/test_shared_pool_c", 4, 4) {
+            Err(Error::InvalidOption { option: "page_size", .. }) => (),
+            other => panic!("expected invalid page size, got {:?}", other.err()),
+        }
+        let mut c = LinHash::open("/tmp/test_shared_pool_c", 4, 4).unwrap();
+        c.close().unwrap();
+        match options().open("/tmp/test_shared_pool_c", 4, 4) {
+            Err(Error::Mismatch { param: "page size", requested: 1024, stored: 4096 }) => (),
+            other => panic!("expected page size mismatch, got {:?}", other.err()),
+        }
+
+        drop((a, b));
+        let mut a = options().open_existing("/tmp/test_shared_pool_a").unwrap();
+        assert_eq!(a.iter().count(), 2000);
+        a.close().unwrap();
+        for name in &["a", "b", "c"] {
+            fs::remove_file(format!("/tmp/test_shared_pool_{}", name)).ok();
+        }
+    }
+
     #[test]
     fn test_persistence() {
-        let mut h = LinHash::open("/tmp/test_persistence", 32, 4);
-        h.put(b"hello", &[12]);
-        h.put(b"world", &[13]);
-        h.put(b"linear", &[144]);
-        h.put(b"hashing", &[255]);
-        h.close();
+        let mut h = LinHash::open("/tmp/test_persistence", 32, 4).unwrap();
+        h.put(b"hello", &[12]).unwrap();
+        h.put(b"world", &[13]).unwrap();
+        h.put(b"linear", &[144]).unwrap();
+        h.put(b"hashing", &[255]).unwrap();
+        h.close().unwrap();
 
         // This reloads the file and creates a new hashtable
-        let mut h2 = LinHash::open("/tmp/test_persistence", 32, 4);
-        assert_eq!(h2.get(b"hello"), Some(vec![12, 0, 0, 0]));
+        let mut h2 = LinHash::open("/tmp/test_persistence", 32, 4).unwrap();
+        assert_eq!(h2.get(b"hello").unwrap(), Some(vec![12]));
 
-        h2.close();
+        h2.close().unwrap();
         fs::remove_file("/tmp/test_persistence").ok();
     }
 
@@ -251,19 +1318,191 @@ mod tests {
     // there.
     #[test]
     fn test_overflow_and_splitting() {
-        let mut h = LinHash::open("/tmp/test_overflow_and_splitting", 4, 4);
+        let mut h = LinHash::open("/tmp/test_overflow_and_splitting", 4, 4).unwrap();
         for k in 0..10000 {
             h.put(&i32_to_bytearray(k),
-                   &i32_to_bytearray(k+1));
+                   &i32_to_bytearray(k+1)).unwrap();
         }
-        h.close();
+        h.close().unwrap();
 
-        let mut h2 = LinHash::open("/tmp/test_overflow_and_splitting", 4, 4);
+        let mut h2 = LinHash::open("/tmp/test_overflow_and_splitting", 4, 4).unwrap();
         for k in 0..10000 {
-            assert_eq!(h2.get(&i32_to_bytearray(k)),
+            assert_eq!(h2.get(&i32_to_bytearray(k)).unwrap(),
                        Some(i32_to_bytearray(k+1).to_vec()));
         }
 
         fs::remove_file("/tmp/test_overflow_and_splitting").ok();
     }
+
+    #[test]
+    fn test_variable_length() {
+        fs::remove_file("/tmp/test_variable_length").ok();
+        let mut h = LinHash::open("/tmp/test_variable_length", 256, 1024).unwrap();
+        for k in 0..2000 {
+            let key = format!("https://example.com/users/{}", k);
+            h.put(key.as_bytes(), &vec![k as u8; k % 50]).unwrap();
+        }
+        // Grow some values past what their page has room for
+        for k in (0..2000).filter(|k| k % 7 == 0) {
+            let key = format!("https://example.com/users/{}", k);
+            assert!(h.update(key.as_bytes(), &vec![k as u8; 1000]).unwrap());
+        }
+        h.close().unwrap();
+
+        let mut h2 = LinHash::open("/tmp/test_variable_length", 256, 1024).unwrap();
+        for k in 0..2000 {
+            let key = format!("https://example.com/users/{}", k);
+            let len = if k % 7 == 0 { 1000 } else { k % 50 };
+            assert_eq!(h2.get(key.as_bytes()).unwrap(), Some(vec![k as u8; len]));
+        }
+        h2.close().unwrap();
+        fs::remove_file("/tmp/test_variable_length").ok();
+    }
+
+    #[test]
+    fn test_iter() {
+        fs::remove_file("/tmp/test_iter").ok();
+        let mut h = LinHash::open("/tmp/test_iter", 4, 4).unwrap();
+        assert_eq!(h.iter().count(), 0);
+        for k in 0..5000 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k+1)).unwrap();
+        }
+        for k in (0..5000).filter(|k| k % 2 == 0) {
+            h.remove(&i32_to_bytearray(k)).unwrap();
+        }
+
+        let mut records: Vec<(Vec<u8>, Vec<u8>)> =
+            h.iter().map(|r| r.unwrap()).collect();
+        records.sort();
+        let mut expected: Vec<(Vec<u8>, Vec<u8>)> = (0..5000)
+            .filter(|k| k % 2 == 1)
+            .map(|k| (i32_to_bytearray(k).to_vec(), i32_to_bytearray(k+1).to_vec()))
+            .collect();
+        expected.sort();
+        assert_eq!(records, expected);
+
+        let mut keys: Vec<Vec<u8>> = h.keys().map(|k| k.unwrap()).collect();
+        keys.sort();
+        assert_eq!(keys, expected.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>());
+        assert_eq!(h.values().count(), 2500);
+
+        h.close().unwrap();
+        fs::remove_file("/tmp/test_iter").ok();
+    }
+
+    #[test]
+    fn test_scan() {
+        fs::remove_file("/tmp/test_scan").ok();
+        let mut h = LinHash::open("/tmp/test_scan", 4, 4).unwrap();
+        for k in 0..3000 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k+1)).unwrap();
+        }
+
+        // Scan half way, then grow the table so that buckets split
+        // and more hash bits are used before finishing the scan.
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        let mut calls = 0;
+        loop {
+            let (next, records) = h.scan(cursor, 100).unwrap();
+            for (k, v) in records {
+                assert_eq!(v.len(), 4);
+                seen.insert(k);
+            }
+            calls += 1;
+            if calls == 5 {
+                let nbits = h.nbits;
+                for k in 3000..12000 {
+                    h.put(&i32_to_bytearray(k), &i32_to_bytearray(k+1)).unwrap();
+                }
+                assert!(h.nbits > nbits);
+            }
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert!(calls > 5);
+        for k in 0..3000 {
+            assert!(seen.contains(&i32_to_bytearray(k)[..]));
+        }
+
+        h.close().unwrap();
+        fs::remove_file("/tmp/test_scan").ok();
+    }
+
+    #[test]
+    fn test_large_directory() {
+        fs::remove_file("/tmp/test_large_directory").ok();
+        // Large values so that the table needs more buckets than the
+        // control page could hold mappings for.
+        let val = [9; 200];
+        let mut h = LinHash::open("/tmp/test_large_directory", 4, 200).unwrap();
+        for k in 0..12000 {
+            h.put(&i32_to_bytearray(k), &val).unwrap();
+        }
+        let nbuckets = h.nbuckets;
+        assert!(nbuckets > 506);
+        h.close().unwrap();
+
+        let mut h2 = LinHash::open("/tmp/test_large_directory", 4, 200).unwrap();
+        assert_eq!(h2.nbuckets, nbuckets);
+        for k in 12000..16000 {
+            h2.put(&i32_to_bytearray(k), &val).unwrap();
+        }
+        h2.close().unwrap();
+
+        let mut h3 = LinHash::open("/tmp/test_large_directory", 4, 200).unwrap();
+        assert!(h3.nbuckets > nbuckets);
+        for k in 0..16000 {
+            assert_eq!(h3.get(&i32_to_bytearray(k)).unwrap(), Some(val.to_vec()));
+        }
+        h3.close().unwrap();
+        fs::remove_file("/tmp/test_large_directory").ok();
+    }
+
+    #[test]
+    fn test_remove() {
+        fs::remove_file("/tmp/test_remove").ok();
+        // Long overflow chains, and no merges
+        let options = || LinHashOptions::new().threshold(4.0).merge_threshold(0.0);
+        let mut h = options().open("/tmp/test_remove", 4, 4).unwrap();
+        for k in 0..2000 {
+            h.put(&i32_to_bytearray(k), &i32_to_bytearray(k+1)).unwrap();
+        }
+        for k in (0..2000).filter(|k| k % 3 != 0) {
+            assert_eq!(h.remove(&i32_to_bytearray(k)).unwrap(),
+                       Some(i32_to_bytearray(k+1).to_vec()));
+        }
+        assert_eq!(h.remove(&i32_to_bytearray(1)).unwrap(), None);
+        h.close().unwrap();
+
+        let mut h2 = options().open("/tmp/test_remove", 4, 4).unwrap();
+        for k in 0..2000 {
+            let expected = if k % 3 == 0 {
+                Some(i32_to_bytearray(k+1).to_vec())
+            } else {
+                None
+            };
+            assert_eq!(h2.get(&i32_to_bytearray(k)).unwrap(), expected);
+        }
+
+        // Freed overflow pages are reused by later inserts, so putting
+        // the removed keys back does not grow the file
+        let len = fs::metadata("/tmp/test_remove").unwrap().len();
+        for k in (0..2000).filter(|k| k % 3 != 0) {
+            h2.put(&i32_to_bytearray(k), &i32_to_bytearray(k+1)).unwrap();
+        }
+        h2.flush().unwrap();
+        assert_eq!(fs::metadata("/tmp/test_remove").unwrap().len(), len);
+        for k in 2000..4000 {
+            h2.put(&i32_to_bytearray(k), &i32_to_bytearray(k+1)).unwrap();
+        }
+        for k in 2000..4000 {
+            assert_eq!(h2.get(&i32_to_bytearray(k)).unwrap(),
+                       Some(i32_to_bytearray(k+1).to_vec()));
+        }
+        h2.close().unwrap();
+        fs::remove_file("/tmp/test_remove").ok();
+    }
 }
@@ -0,0 +1,240 @@
+use error::{Error, Result};
+use hash::KeyHasher;
+use page::Page;
+use std::sync::Arc;
+
+use pool::{BufferPool, Replacement, DEFAULT_CACHE_CAPACITY};
+use LinHash;
+
+/// When the control page, which holds the size of the table and
+/// where its pages are, is written to disk, along with the changed
+/// pages it refers to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    /// Only when the table is flushed or closed.
+    OnClose,
+    /// After every `n` calls that change the table: `Every(1)` writes
+    /// it after each `put`, `update` and `remove`.
+    Every(usize),
+}
+
+/// Options for opening a `LinHash`, in the style of
+/// `std::fs::OpenOptions`:
+///
+/// ```no_run
+/// use linhash::LinHashOptions;
+///
+/// let table = LinHashOptions::new()
+///     .threshold(0.5)
+///     .page_size(8192)
+///     .open("/tmp/lookups", 16, 64);
+/// ```
+pub struct LinHashOptions {
+    pub(crate) threshold: f32,
+    pub(crate) merge_threshold: Option<f32>,
+    pub(crate) page_size: Option<usize>,
+    pub(crate) cache_capacity: usize,
+    pub(crate) durability: Durability,
+    pub(crate) replacement: Replacement,
+    pub(crate) create: bool,
+    pub(crate) truncate: bool,
+    pub(crate) wal: bool,
+    pub(crate) shadow_paging: Option<bool>,
+    pub(crate) verify_checksums: bool,
+    pub(crate) hasher: Option<Box<dyn KeyHasher>>,
+    pub(crate) buffer_pool: Option<Arc<BufferPool>>,
+}
+
+impl LinHashOptions {
+    pub fn new() -> LinHashOptions {
+        LinHashOptions {
+            threshold: 0.8,
+            merge_threshold: None,
+            page_size: None,
+            cache_capacity: DEFAULT_CACHE_CAPACITY,
+            durability: Durability::OnClose,
+            replacement: Replacement::Lru,
+            create: true,
+            truncate: false,
+            wal: false,
+            shadow_paging: None,
+            verify_checksums: true,
+            hasher: None,
+            buffer_pool: None,
+        }
+    }
+
+    /// Load factor above which a bucket is split, as the fraction of
+    /// bucket page space taken up by records. Lower values make
+    /// lookups faster, higher values make the file smaller. Defaults
+    /// to 0.8.
+    pub fn threshold(mut self, threshold: f32) -> LinHashOptions {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Load factor below which the last bucket is merged back into
+    /// the bucket it was split from, so that the file shrinks after
+    /// many records are removed. It must be less than half of
+    /// `threshold`, and 0 turns merging off. Defaults to a quarter of
+    /// `threshold`.
+    pub fn merge_threshold(mut self, merge_threshold: f32) -> LinHashOptions {
+        self.merge_threshold = Some(merge_threshold);
+        self
+    }
+
+    /// Size of a page in bytes: a power of two between 512 and 32768.
+    /// It is stored in the table when it is created. If this is not
+    /// set, new tables use 4096 byte pages and existing tables the
+    /// size they were created with.
+    pub fn page_size(mut self, page_size: usize) -> LinHashOptions {
+        self.page_size = Some(page_size);
+        self
+    }
+
+    /// Bytes of pages kept in memory. At least two pages are kept
+    /// however small this is. Defaults to 64 KiB; it can be changed
+    /// later with `LinHash::set_cache_capacity`. Ignored with a shared
+    /// `buffer_pool`.
+    pub fn cache_capacity(mut self, cache_capacity: usize) -> LinHashOptions {
+        self.cache_capacity = cache_capacity;
+        self
+    }
+
+    /// Which page is evicted when the buffer pool is full. Defaults to
+    /// `Replacement::Lru`. Ignored with a shared `buffer_pool`.
+    pub fn replacement(mut self, replacement: Replacement) -> LinHashOptions {
+        self.replacement = replacement;
+        self
+    }
+
+    /// When the control page is written. Defaults to
+    /// `Durability::OnClose`.
+    pub fn durability(mut self, durability: Durability) -> LinHashOptions {
+        self.durability = durability;
+        self
+    }
+
+    /// Create the file if it does not exist. Defaults to true.
+    pub fn create(mut self, create: bool) -> LinHashOptions {
+        self.create = create;
+        self
+    }
+
+    /// Throw away any table already in the file. Defaults to false.
+    pub fn truncate(mut self, truncate: bool) -> LinHashOptions {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Keep a write-ahead log in `<filename>-wal`, so that a crash
+    /// never leaves the table half changed. Changes are committed to
+    /// the log whenever `durability` has the control page written,
+    /// and after a crash the table is opened as it was at the last
+    /// commit. `LinHash::flush` and `close` copy the log into the
+    /// table and empty it. Defaults to false.
+    pub fn wal(mut self, wal: bool) -> LinHashOptions {
+        self.wal = wal;
+        self
+    }
+
+    /// Never write over the pages of the last commit: changed pages go
+    /// to free pages, and a commit switches the control page to the
+    /// new version. After a crash the table is opened as it was at the
+    /// last commit, without a log to replay. Cannot be combined with
+    /// `wal`. Tables keep the mode they were created with. Defaults to
+    /// false.
+    pub fn shadow_paging(mut self, shadow_paging: bool) -> LinHashOptions {
+        self.shadow_paging = Some(shadow_paging);
+        self
+    }
+
+    /// Check the checksum of every page read from the file, so that a
+    /// damaged page is reported as `Error::Corrupt` instead of being
+    /// read as wrong records. Checksums are always written; turning
+    /// this off only saves the work of checking them. Defaults to
+    /// true.
+    pub fn verify_checksums(mut self, verify: bool) -> LinHashOptions {
+        self.verify_checksums = verify;
+        self
+    }
+
+    /// Hash keys with `hasher`. See `LinHash::open_with_hasher`.
+    pub fn hasher(mut self, hasher: Box<dyn KeyHasher>) -> LinHashOptions {
+        self.hasher = Some(hasher);
+        self
+    }
+
+    /// Keep pages in `pool`, shared with other tables, instead of a
+    /// pool of the table's own. The table must have the page size of
+    /// the pool.
+    pub fn buffer_pool(mut self, pool: Arc<BufferPool>) -> LinHashOptions {
+        self.buffer_pool = Some(pool);
+        self
+    }
+
+    /// Opens the table in `filename`, like `LinHash::open`.
+    pub fn open(self, filename: &str, keysize: usize, valsize: usize) -> Result<LinHash> {
+        LinHash::open_table(filename, Some((keysize, valsize)), self)
+    }
+
+    /// Opens an existing table in `filename`, like
+    /// `LinHash::open_existing`.
+    pub fn open_existing(self, filename: &str) -> Result<LinHash> {
+        LinHash::open_table(filename, None, self)
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if !(self.threshold > 0.0 && self.threshold.is_finite()) {
+            return Err(Error::InvalidOption {
+                option: "threshold",
+                reason: "must be a positive number",
+            });
+        }
+        if let Some(merge_threshold) = self.merge_threshold {
+            // Merging must leave the load below `threshold`, or the
+            // next insert splits the bucket again.
+            if !(merge_threshold >= 0.0 && merge_threshold < self.threshold / 2.0) {
+                return Err(Error::InvalidOption {
+                    option: "merge_threshold",
+                    reason: "must be at least 0 and less than half of threshold",
+                });
+            }
+        }
+        if let Some(page_size) = self.page_size {
+            if !Page::valid_size(page_size) {
+                return Err(Error::InvalidOption {
+                    option: "page_size",
+                    reason: "must be a power of two between 512 and 32768",
+                });
+            }
+        }
+        if let (Some(page_size), Some(ref pool)) = (self.page_size, &self.buffer_pool) {
+            if page_size != pool.page_size() {
+                return Err(Error::InvalidOption {
+                    option: "page_size",
+                    reason: "must be the page size of the buffer pool",
+                });
+            }
+        }
+        if self.wal && self.shadow_paging == Some(true) {
+            return Err(Error::InvalidOption {
+                option: "shadow_paging",
+                reason: "cannot be combined with wal",
+            });
+        }
+        if self.durability == Durability::Every(0) {
+            return Err(Error::InvalidOption {
+                option: "durability",
+                reason: "must write after at least 1 change",
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Default for LinHashOptions {
+    fn default() -> LinHashOptions {
+        LinHashOptions::new()
+    }
+}
@@ -1,22 +1,41 @@
+use hash::{KeyHasher, XxHash64};
 use util::*;
 
-pub const PAGE_SIZE : usize = 4096; // bytes
-pub const HEADER_SIZE : usize = 16; // bytes
+pub const DEFAULT_PAGE_SIZE : usize = 4096; // bytes
+// Slots hold 16-bit offsets into the page
+pub const MIN_PAGE_SIZE : usize = 512;
+pub const MAX_PAGE_SIZE : usize = 32768;
+pub const HEADER_SIZE : usize = 32; // bytes
+// The checksum is the last header field
+const CHECKSUM_OFFSET : usize = 24;
+pub const SLOT_SIZE : usize = 6; // bytes
 
 pub struct Page {
     pub id: usize,
-    pub storage: [u8; PAGE_SIZE],
+    pub storage: Vec<u8>,
     pub num_records: usize,
     // page_id of overflow bucket
     pub next: Option<usize>,
     pub dirty: bool,
 
-    keysize: usize,
-    valsize: usize,
+    // offset of the lowest byte used by record data
+    data_start: usize,
 }
 
+// Page layout:
+// | header | slot 0 | slot 1 | ... -> free space <- ... | row 1 | row 0 |
+//
+// Header layout, each field 8 bytes long:
+// | num_records | next | data_start | checksum |
+//
+// Slot layout:
+// | offset | key_len | val_len |
+//
 // Row layout:
 // | key | val |
+//
+// Rows are kept packed against the end of the page, so the free space
+// is always the gap between the slot directory and `data_start`.
 #[derive(Debug)]
 struct RowOffsets {
     key_offset: usize,
@@ -25,71 +44,282 @@ struct RowOffsets {
 }
 
 impl Page {
-    pub fn new(keysize: usize, valsize: usize) -> Page {
+    pub fn new(page_size: usize) -> Page {
         Page {
             id: 0,
             num_records: 0,
-            storage: [0; PAGE_SIZE],
+            storage: vec![0; page_size],
             next: None,
-            keysize: keysize,
-            valsize: valsize,
             dirty: false,
+            data_start: page_size,
         }
     }
 
-    /// Compute where in the page the row should be placed. Within the
-    /// row, calculate the offsets of the header, key and value.
+    /// Drops all records and the link to the next page.
+    pub fn clear(&mut self) {
+        for b in self.storage.iter_mut() {
+            *b = 0;
+        }
+        self.num_records = 0;
+        self.next = None;
+        self.data_start = self.storage.len();
+    }
+
+    /// Is `page_size` a size pages can have?
+    pub fn valid_size(page_size: usize) -> bool {
+        page_size.is_power_of_two() &&
+            (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size)
+    }
+
+    /// Bytes taken up in a page by a record with a key of `key_len`
+    /// and a value of `val_len` bytes.
+    pub fn record_size(key_len: usize, val_len: usize) -> usize {
+        SLOT_SIZE + key_len + val_len
+    }
+
+    /// Bytes available for records in an empty page of `page_size`
+    /// bytes.
+    pub fn capacity(page_size: usize) -> usize {
+        page_size - HEADER_SIZE
+    }
+
+    fn slot_offset(row_num: usize) -> usize {
+        HEADER_SIZE + row_num * SLOT_SIZE
+    }
+
+    fn read_slot(&self, row_num: usize) -> (usize, usize, usize) {
+        let slot = Page::slot_offset(row_num);
+        (bytearray_to_u16(&self.storage[slot..slot+2]) as usize,
+         bytearray_to_u16(&self.storage[slot+2..slot+4]) as usize,
+         bytearray_to_u16(&self.storage[slot+4..slot+6]) as usize)
+    }
+
+    fn write_slot(&mut self, row_num: usize,
+                  (offset, key_len, val_len): (usize, usize, usize)) {
+        let slot = Page::slot_offset(row_num);
+        mem_move(&mut self.storage[slot..slot+2], &u16_to_bytearray(offset as u16));
+        mem_move(&mut self.storage[slot+2..slot+4], &u16_to_bytearray(key_len as u16));
+        mem_move(&mut self.storage[slot+4..slot+6], &u16_to_bytearray(val_len as u16));
+    }
+
+    /// Find where in the page the row is placed, using its slot.
     fn compute_offsets(&self, row_num: usize) -> RowOffsets {
-        let total_size = self.keysize + self.valsize;
+        let (offset, key_len, val_len) = self.read_slot(row_num);
 
-        let row_offset = HEADER_SIZE + (row_num * total_size);
-        let key_offset = row_offset;
-        let val_offset = key_offset + self.keysize;
-        let row_end = val_offset + self.valsize;
+        let key_offset = offset;
+        let val_offset = key_offset + key_len;
+        let row_end = val_offset + val_len;
 
         RowOffsets {
-            key_offset: key_offset,
-            val_offset: val_offset,
-            row_end: row_end,
+            key_offset,
+            val_offset,
+            row_end,
         }
     }
 
-
     pub fn read_header(&mut self) {
         let num_records : usize = bytearray_to_usize(self.storage[0..8].to_vec());
         let next : usize = bytearray_to_usize(self.storage[8..16].to_vec());
+        let data_start : usize = bytearray_to_usize(self.storage[16..24].to_vec());
         self.num_records = num_records;
         self.next = if next != 0 {
             Some(next)
         } else {
             None
         };
+        // A page that was never written is all zeroes
+        self.data_start = if data_start != 0 {
+            data_start
+        } else {
+            self.storage.len()
+        };
     }
 
+    /// Writes the header fields to `storage`, and last the checksum of
+    /// the whole page.
     pub fn write_header(&mut self) {
         mem_move(&mut self.storage[0..8], &usize_to_bytearray(self.num_records));
         mem_move(&mut self.storage[8..16], &usize_to_bytearray(self.next.unwrap_or(0)));
+        mem_move(&mut self.storage[16..24], &usize_to_bytearray(self.data_start));
+        let checksum = self.checksum();
+        mem_move(&mut self.storage[CHECKSUM_OFFSET..HEADER_SIZE],
+                 &usize_to_bytearray(checksum as usize));
+    }
+
+    // Checksum of the page id and everything in `storage` but the
+    // checksum field, so that a page read from or written to the wrong
+    // place does not pass either
+    fn checksum(&self) -> u64 {
+        XxHash64.hash(&usize_to_bytearray(self.id)).rotate_left(2) ^
+            XxHash64.hash(&self.storage[..CHECKSUM_OFFSET]).rotate_left(1) ^
+            XxHash64.hash(&self.storage[HEADER_SIZE..])
+    }
+
+    /// Does the checksum stored in `storage` match its contents and
+    /// `id`? A page that was never written has no checksum, and is not
+    /// valid.
+    pub fn checksum_valid(&self) -> bool {
+        let stored = bytearray_to_usize(self.storage[CHECKSUM_OFFSET..HEADER_SIZE].to_vec());
+        stored as u64 == self.checksum()
     }
 
-    pub fn read_record(&mut self, row_num: usize) -> (&[u8], &[u8]) {
+    /// Bytes available for a new record, including its slot.
+    pub fn free_space(&self) -> usize {
+        self.data_start - Page::slot_offset(self.num_records)
+    }
+
+    /// Can a new record with a key of `key_len` and a value of
+    /// `val_len` bytes be added to this page?
+    pub fn fits(&self, key_len: usize, val_len: usize) -> bool {
+        Page::record_size(key_len, val_len) <= self.free_space()
+    }
+
+    pub fn read_record(&self, row_num: usize) -> (&[u8], &[u8]) {
         let offsets = self.compute_offsets(row_num);
         let key = &self.storage[offsets.key_offset..offsets.val_offset];
         let val = &self.storage[offsets.val_offset..offsets.row_end];
         (key, val)
     }
 
-    /// Write record to offset specified by `row_num`. The offset is
-    /// calculated to accomodate header as well.
-    pub fn write_record(&mut self, row_num: usize, key: &[u8], val: &[u8]) {
-        let offsets = self.compute_offsets(row_num);
-        mem_move(&mut self.storage[offsets.key_offset..offsets.val_offset],
-                 key);
-        mem_move(&mut self.storage[offsets.val_offset..offsets.row_end],
-                 val);
+    /// Add a new record to the end of the slot directory. The caller
+    /// must check that it `fits`.
+    pub fn insert_record(&mut self, key: &[u8], val: &[u8]) {
+        let row_num = self.num_records;
+        self.num_records += 1;
+        self.place_record(row_num, key, val);
     }
 
-    /// Increment number of records in page
-    pub fn incr_num_records(&mut self) {
-        self.num_records += 1;
+    /// Overwrite record `row_num`. Returns false, leaving the page
+    /// untouched, if the new record does not fit in the page.
+    pub fn write_record(&mut self, row_num: usize, key: &[u8], val: &[u8]) -> bool {
+        let (_, old_key_len, old_val_len) = self.read_slot(row_num);
+        if key.len() + val.len() > self.free_space() + old_key_len + old_val_len {
+            return false;
+        }
+        self.remove_data(row_num);
+        self.place_record(row_num, key, val);
+        true
+    }
+
+    /// Delete record `row_num`. The last record of the page takes its
+    /// slot so that rows `0..num_records` stay dense.
+    pub fn remove_record(&mut self, row_num: usize) {
+        self.remove_data(row_num);
+        let last_row = self.num_records - 1;
+        if row_num != last_row {
+            let last_slot = self.read_slot(last_row);
+            self.write_slot(row_num, last_slot);
+        }
+        self.num_records -= 1;
+    }
+
+    /// Copy `key` and `val` to the start of the data area and point
+    /// slot `row_num` at them.
+    fn place_record(&mut self, row_num: usize, key: &[u8], val: &[u8]) {
+        let offset = self.data_start - key.len() - val.len();
+        let val_offset = offset + key.len();
+        self.storage[offset..val_offset].copy_from_slice(key);
+        self.storage[val_offset..self.data_start].copy_from_slice(val);
+        self.data_start = offset;
+        self.write_slot(row_num, (offset, key.len(), val.len()));
+    }
+
+    /// Release the data of record `row_num`, shifting every row placed
+    /// below it up to close the gap. The slot itself is left stale.
+    fn remove_data(&mut self, row_num: usize) {
+        let (offset, key_len, val_len) = self.read_slot(row_num);
+        let len = key_len + val_len;
+        if len == 0 {
+            return;
+        }
+
+        self.storage.copy_within(self.data_start..offset, self.data_start + len);
+        for row in 0..self.num_records {
+            let (row_offset, row_key_len, row_val_len) = self.read_slot(row);
+            if row_offset < offset {
+                self.write_slot(row, (row_offset + len, row_key_len, row_val_len));
+            }
+        }
+        self.data_start += len;
+        self.write_slot(row_num, (self.data_start, 0, 0));
+    }
+}
+
+impl Default for Page {
+    fn default() -> Page {
+        Page::new(DEFAULT_PAGE_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use page::{Page, DEFAULT_PAGE_SIZE};
+
+    #[test]
+    fn variable_length_records() {
+        let mut p = Page::new(DEFAULT_PAGE_SIZE);
+        p.insert_record(b"a", b"first");
+        p.insert_record(b"longer key", b"");
+        p.insert_record(b"third", b"3");
+        assert_eq!(p.read_record(1), (&b"longer key"[..], &b""[..]));
+
+        // Growing a record in the middle keeps its neighbours intact
+        assert!(p.write_record(1, b"longer key", b"now with a value"));
+        assert_eq!(p.read_record(0), (&b"a"[..], &b"first"[..]));
+        assert_eq!(p.read_record(1), (&b"longer key"[..], &b"now with a value"[..]));
+        assert_eq!(p.read_record(2), (&b"third"[..], &b"3"[..]));
+
+        p.remove_record(0);
+        assert_eq!(p.num_records, 2);
+        assert_eq!(p.read_record(0), (&b"third"[..], &b"3"[..]));
+        assert_eq!(p.read_record(1), (&b"longer key"[..], &b"now with a value"[..]));
+        assert_eq!(p.free_space(),
+                   Page::capacity(DEFAULT_PAGE_SIZE) - Page::record_size(5, 1) - Page::record_size(10, 16));
+
+        // Header round trip
+        p.write_header();
+        let mut p2 = Page::new(DEFAULT_PAGE_SIZE);
+        p2.storage.copy_from_slice(&p.storage);
+        p2.read_header();
+        assert_eq!(p2.num_records, 2);
+        assert_eq!(p2.free_space(), p.free_space());
+        assert_eq!(p2.read_record(1), (&b"longer key"[..], &b"now with a value"[..]));
+    }
+
+    #[test]
+    fn checksum() {
+        let mut p = Page::new(DEFAULT_PAGE_SIZE);
+        assert!(!p.checksum_valid());
+        p.id = 5;
+        p.insert_record(b"key", b"value");
+        p.write_header();
+        assert!(p.checksum_valid());
+
+        // The same bytes are not valid as another page
+        p.id = 6;
+        assert!(!p.checksum_valid());
+        p.id = 5;
+
+        // A flipped bit anywhere is caught, in the header or the rows
+        for &offset in &[0, DEFAULT_PAGE_SIZE - 1] {
+            p.storage[offset] ^= 1;
+            assert!(!p.checksum_valid());
+            p.storage[offset] ^= 1;
+        }
+        assert!(p.checksum_valid());
+    }
+
+    #[test]
+    fn full_page() {
+        let mut p = Page::new(DEFAULT_PAGE_SIZE);
+        let val = [7; 100];
+        let mut n = 0;
+        while p.fits(4, val.len()) {
+            p.insert_record(&(n as u32).to_ne_bytes(), &val);
+            n += 1;
+        }
+        assert_eq!(n, Page::capacity(DEFAULT_PAGE_SIZE) / Page::record_size(4, 100));
+        assert!(!p.write_record(0, b"key", &[0; 300]));
+        assert_eq!(p.read_record(0), (&0u32.to_ne_bytes()[..], &val[..]));
     }
 }
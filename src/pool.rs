@@ -0,0 +1,1072 @@
+use std::cmp;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::ops::{Deref, DerefMut, Range};
+use std::os::unix::fs::FileExt;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use error::{Error, Result};
+use page::Page;
+use shadow::Shadow;
+use wal::Wal;
+
+/// Identifies a page in a buffer pool: the id its file was registered
+/// with and its page id in that file.
+pub type PageKey = (usize, usize);
+
+/// Decides which page of the buffer pool is evicted when another page
+/// has to be read in. Frames are the slots of the pool, numbered from
+/// 0, and each holds one page.
+pub trait ReplacementPolicy: Send {
+    /// The page with `key` was read into `frame`.
+    fn loaded(&mut self, frame: usize, key: PageKey);
+
+    /// The page in `frame` was used again.
+    fn touched(&mut self, frame: usize);
+
+    /// The page in `frame` was dropped without being evicted, and the
+    /// frame is unused until a page is loaded into it.
+    fn removed(&mut self, frame: usize);
+
+    /// Picks the frame whose page is evicted next, skipping frames
+    /// for which `pinned` is true; unused frames count as pinned.
+    /// Returns None if every frame is pinned.
+    fn victim(&mut self, pinned: &dyn Fn(usize) -> bool) -> Option<usize>;
+}
+
+/// The replacement policies a table can be opened with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Replacement {
+    /// Evict the least recently used page.
+    Lru,
+    /// Approximate LRU: a hand sweeps the frames, evicting the first
+    /// page not used since the hand last passed it.
+    Clock,
+    /// Pages used only once are evicted first, so a scan over the
+    /// table does not push out pages that are used over and over.
+    TwoQ,
+}
+
+impl Replacement {
+    pub fn policy(self, num_frames: usize) -> Box<dyn ReplacementPolicy> {
+        match self {
+            Replacement::Lru => Box::new(Lru::new(num_frames)),
+            Replacement::Clock => Box::new(Clock::new(num_frames)),
+            Replacement::TwoQ => Box::new(TwoQ::new(num_frames)),
+        }
+    }
+}
+
+/// Buffer pool hit and miss counts, see `LinHash::pool_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PoolStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Bytes of pages a buffer pool holds unless told otherwise: 16
+/// pages of 4096 bytes.
+pub const DEFAULT_CACHE_CAPACITY: usize = 64 * 1024;
+
+// Moving records between pages pins two of them at once, so a pool
+// never holds fewer pages than this.
+const MIN_FRAMES: usize = 2;
+
+/// Reads page `page_id` of `file` into `buf`. A page past the end of
+/// the file was never written, and is read as a fresh page of zeroes;
+/// false is returned then. A page the file ends in the middle of is an
+/// error.
+pub(crate) fn read_page(file: &File, page_id: usize, buf: &mut [u8]) -> Result<bool> {
+    let offset = (page_id * buf.len()) as u64;
+    match file.read_exact_at(buf, offset) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            if offset < file.metadata()?.len() {
+                return Err(Error::Corrupt { page_id, reason: "page cut short by end of file" });
+            }
+            for b in buf.iter_mut() {
+                *b = 0;
+            }
+            Ok(false)
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(crate) fn write_page(file: &File, page_id: usize, data: &[u8]) -> Result<()> {
+    file.write_all_at(data, (page_id * data.len()) as u64)?;
+    Ok(())
+}
+
+/// Writes the bytes in `range` of the control page `ctrl` over page 0
+/// of `file`, leaving the rest of it alone so that a torn write cannot
+/// damage it. A file that does not hold page 0 yet gets all of it.
+pub(crate) fn write_ctrl(file: &File, ctrl: &[u8], range: Range<usize>) -> Result<()> {
+    if file.metadata()?.len() < ctrl.len() as u64 {
+        return write_page(file, 0, ctrl);
+    }
+    file.write_all_at(&ctrl[range.clone()], range.start as u64)?;
+    Ok(())
+}
+
+/// How changed pages of a file are written back.
+pub enum Journal {
+    /// Over the old version of the page.
+    InPlace,
+    /// To a write-ahead log, see `Wal`.
+    Wal(Wal),
+    /// To a free page, see `Shadow`.
+    Shadow(Shadow),
+}
+
+// A file registered with the pool
+struct PoolFile {
+    file: File,
+    journal: Journal,
+    // are page checksums checked when pages are read?
+    verify_checksums: bool,
+    // ids of the buffered pages changed since they were written back
+    dirty: BTreeSet<usize>,
+}
+
+impl PoolFile {
+    // Returns false if the page was never written, and is all zeroes
+    fn read_page(&self, page_id: usize, buf: &mut [u8]) -> Result<bool> {
+        match self.journal {
+            Journal::Wal(ref wal) if wal.read_page(page_id, buf)? => Ok(true),
+            Journal::Shadow(ref shadow) if page_id != 0 => {
+                shadow.read_page(&self.file, page_id, buf)
+            },
+            _ => read_page(&self.file, page_id, buf),
+        }
+    }
+
+    // Writes `page` back and marks it clean.
+    fn write_back(&mut self, page: &mut Page) -> Result<()> {
+        page.write_header();
+        match self.journal {
+            Journal::InPlace => write_page(&self.file, page.id, &page.storage)?,
+            Journal::Wal(ref mut wal) => wal.append_page(page.id, &page.storage)?,
+            Journal::Shadow(ref mut shadow) => {
+                shadow.write_page(&self.file, page.id, &page.storage)?
+            },
+        }
+        page.dirty = false;
+        self.dirty.remove(&page.id);
+        Ok(())
+    }
+
+    // Stores the control page `ctrl`, committing the pages written
+    // back since the last commit. See `BufferPool::commit` for `seal`.
+    // Returns the bytes of `ctrl` that `seal` changed.
+    fn commit<F>(&mut self, ctrl: &mut [u8], seal: F) -> Result<Range<usize>>
+        where F: FnOnce(&mut [u8], usize) -> Range<usize>
+    {
+        match self.journal {
+            Journal::InPlace => {
+                let sealed = seal(ctrl, 0);
+                write_ctrl(&self.file, ctrl, sealed.clone())?;
+                Ok(sealed)
+            },
+            Journal::Wal(ref mut wal) => {
+                let sealed = seal(ctrl, 0);
+                wal.append_page(0, ctrl)?;
+                wal.commit()?;
+                // Everything in the log is committed now
+                if wal.is_full() {
+                    wal.checkpoint(&self.file, Some(sealed.clone()))?;
+                }
+                Ok(sealed)
+            },
+            Journal::Shadow(ref mut shadow) => shadow.commit(&self.file, ctrl, seal),
+        }
+    }
+}
+
+struct Frame {
+    // page the frame holds, None if it is unused
+    key: Option<PageKey>,
+    // None while the page is pinned, as the guard holds it then
+    page: Option<Page>,
+}
+
+impl Frame {
+    fn unused() -> Frame {
+        Frame { key: None, page: None }
+    }
+}
+
+struct PoolState {
+    page_size: usize,
+    capacity: usize,
+    // files pages are read from, by the id they were registered with
+    files: HashMap<usize, PoolFile>,
+    next_file_id: usize,
+    frames: Vec<Frame>,
+    free_frames: Vec<usize>,
+    // frame each buffered page is in
+    page_table: HashMap<PageKey, usize>,
+    policy: Box<dyn ReplacementPolicy>,
+    stats: PoolStats,
+}
+
+impl PoolState {
+    fn file(&self, file_id: usize) -> &PoolFile {
+        self.files.get(&file_id).expect("file is not registered with the pool")
+    }
+
+    fn file_mut(&mut self, file_id: usize) -> &mut PoolFile {
+        self.files.get_mut(&file_id).expect("file is not registered with the pool")
+    }
+
+    /// Writes the dirty pages of `file_id` back, in page id order so
+    /// that the writes are sequential. Pinned pages are skipped.
+    fn write_dirty(&mut self, file_id: usize) -> Result<()> {
+        let file = self.files.get_mut(&file_id).expect("file is not registered with the pool");
+        let dirty: Vec<usize> = file.dirty.iter().cloned().collect();
+        for page_id in dirty {
+            let frame = self.page_table[&(file_id, page_id)];
+            if let Some(ref mut page) = self.frames[frame].page {
+                file.write_back(page)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the page in `frame`, writing it back first if it is
+    /// dirty. The frame must have been picked by `policy.victim`.
+    fn evict(&mut self, frame: usize) -> Result<()> {
+        let key = self.frames[frame].key.expect("evicted frame holds no page");
+        if let Some(ref mut page) = self.frames[frame].page {
+            if page.dirty {
+                let file = self.files.get_mut(&key.0).expect("file is not registered with the pool");
+                if let Err(e) = file.write_back(page) {
+                    self.policy.loaded(frame, key);
+                    return Err(e);
+                }
+            }
+        }
+        self.frames[frame] = Frame::unused();
+        self.page_table.remove(&key);
+        Ok(())
+    }
+
+    /// Lays the buffered pages out in the first frames of a pool of
+    /// `num_frames` frames, or more if that many pages are buffered,
+    /// with a new replacement policy.
+    fn rebuild(&mut self, num_frames: usize, replacement: Replacement) {
+        let frames: Vec<Frame> = mem::take(&mut self.frames)
+            .into_iter().filter(|frame| frame.key.is_some()).collect();
+        let num_frames = cmp::max(num_frames, frames.len());
+        self.policy = replacement.policy(num_frames);
+        self.page_table.clear();
+        for (frame, key) in frames.iter().filter_map(|frame| frame.key).enumerate() {
+            self.page_table.insert(key, frame);
+            self.policy.loaded(frame, key);
+        }
+        self.free_frames = (frames.len()..num_frames).rev().collect();
+        self.frames = frames;
+        self.frames.resize_with(num_frames, Frame::unused);
+    }
+}
+
+/// Pages kept in memory, as many as fit in its capacity in bytes. A
+/// pool can be shared through an `Arc` by all tables in a process,
+/// which then have one memory budget and one replacement policy:
+///
+/// ```no_run
+/// use std::sync::Arc;
+/// use linhash::{BufferPool, LinHashOptions, Replacement};
+///
+/// let pool = Arc::new(BufferPool::new(4096, 64 << 20, Replacement::Lru).unwrap());
+/// let users = LinHashOptions::new().buffer_pool(pool.clone())
+///     .open("/tmp/users", 16, 64);
+/// let orders = LinHashOptions::new().buffer_pool(pool)
+///     .open("/tmp/orders", 16, 256);
+/// ```
+///
+/// Each table registers its file with the pool and pages are keyed
+/// by (file id, page id). Pages are accessed through guards returned
+/// by `pin` and `pin_mut`, and a page is not evicted while a guard on
+/// it is alive. Changed pages are written back to their file when they
+/// are evicted.
+pub struct BufferPool {
+    replacement: Replacement,
+    state: Mutex<PoolState>,
+}
+
+impl BufferPool {
+    /// A pool of `page_size` byte pages holding up to `capacity`
+    /// bytes of them, but at least two pages. `page_size` has to be a
+    /// size tables can have, see `LinHashOptions::page_size`.
+    pub fn new(page_size: usize, capacity: usize,
+               replacement: Replacement) -> Result<BufferPool> {
+        if !Page::valid_size(page_size) {
+            return Err(Error::InvalidOption {
+                option: "page_size",
+                reason: "must be a power of two between 512 and 32768",
+            });
+        }
+        let num_frames = BufferPool::frames_for(capacity, page_size);
+        Ok(BufferPool {
+            replacement,
+            state: Mutex::new(PoolState {
+                page_size,
+                capacity,
+                files: HashMap::new(),
+                next_file_id: 0,
+                frames: (0..num_frames).map(|_| Frame::unused()).collect(),
+                free_frames: (0..num_frames).rev().collect(),
+                page_table: HashMap::with_capacity(num_frames),
+                policy: replacement.policy(num_frames),
+                stats: PoolStats::default(),
+            }),
+        })
+    }
+
+    fn frames_for(capacity: usize, page_size: usize) -> usize {
+        cmp::max(capacity / page_size, MIN_FRAMES)
+    }
+
+    // A panic while the lock is held leaves the state consistent, so
+    // a poisoned lock is used anyway.
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.lock().page_size
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.lock().capacity
+    }
+
+    /// Hit and miss counts of every table using the pool.
+    pub fn stats(&self) -> PoolStats {
+        self.lock().stats
+    }
+
+    /// Adds `file` to the files pages are read from, returning the id
+    /// its pages are keyed by. Changed pages are written back as
+    /// `journal` says.
+    pub fn register(&self, file: File, journal: Journal) -> usize {
+        let mut state = self.lock();
+        let file_id = state.next_file_id;
+        state.next_file_id += 1;
+        state.files.insert(file_id, PoolFile {
+            file,
+            journal,
+            verify_checksums: true,
+            dirty: BTreeSet::new(),
+        });
+        file_id
+    }
+
+    /// Drops the pages of `file_id` without writing them back, and
+    /// closes the file.
+    pub fn unregister(&self, file_id: usize) {
+        let mut state = self.lock();
+        let state = &mut *state;
+        state.files.remove(&file_id);
+        let keys: Vec<PageKey> = state.page_table.keys()
+            .filter(|key| key.0 == file_id).cloned().collect();
+        for key in keys {
+            if let Some(frame) = state.page_table.remove(&key) {
+                state.frames[frame] = Frame::unused();
+                state.policy.removed(frame);
+                state.free_frames.push(frame);
+            }
+        }
+    }
+
+    /// Check the checksums of pages read from `file_id`, returning
+    /// `Error::Corrupt` for a page that does not match. On by default.
+    pub fn set_verify_checksums(&self, file_id: usize, verify: bool) {
+        self.lock().file_mut(file_id).verify_checksums = verify;
+    }
+
+    /// Writes the changed pages of `file_id` to the write-ahead log
+    /// at `path` from now on, see `Wal`.
+    pub fn use_wal(&self, file_id: usize, path: &str) -> Result<()> {
+        let mut state = self.lock();
+        let pool_file = state.file_mut(file_id);
+        pool_file.journal = Journal::Wal(Wal::open(path, &pool_file.file)?);
+        Ok(())
+    }
+
+    /// Switches `file_id` to shadow paging, with its page map starting
+    /// at page `map_root`. See `Shadow`. Must be called before any of
+    /// its pages is buffered.
+    pub fn use_shadow_paging(&self, file_id: usize, map_root: usize) -> Result<()> {
+        let mut state = self.lock();
+        let page_size = state.page_size;
+        let pool_file = state.file_mut(file_id);
+        pool_file.journal = Journal::Shadow(Shadow::open(&pool_file.file, page_size, map_root)?);
+        Ok(())
+    }
+
+    /// Use pages of `page_size` bytes. Only allowed while the pool
+    /// holds no pages.
+    pub fn set_page_size(&self, page_size: usize) {
+        let mut state = self.lock();
+        assert!(state.page_table.is_empty(), "page size changed while pages are buffered");
+        state.page_size = page_size;
+        let num_frames = BufferPool::frames_for(state.capacity, page_size);
+        state.rebuild(num_frames, self.replacement);
+    }
+
+    /// Resizes the pool to `capacity` bytes, keeping at least two
+    /// pages. When it shrinks, the pages the replacement policy picks
+    /// are written back if they are dirty and dropped; pinned pages
+    /// are kept. The policy forgets how recently the remaining pages
+    /// were used.
+    pub fn set_capacity(&self, capacity: usize) -> Result<()> {
+        let mut state = self.lock();
+        let state = &mut *state;
+        let num_frames = BufferPool::frames_for(capacity, state.page_size);
+        let mut num_used = state.frames.len() - state.free_frames.len();
+        let mut result = Ok(());
+        while num_used > num_frames {
+            let frame = {
+                let frames = &state.frames;
+                state.policy.victim(&|frame| frames[frame].page.is_none())
+            };
+            let frame = match frame {
+                Some(frame) => frame,
+                None => break,
+            };
+            result = state.evict(frame);
+            if result.is_err() {
+                break;
+            }
+            num_used -= 1;
+        }
+        if result.is_ok() {
+            state.capacity = capacity;
+        }
+        let num_frames = BufferPool::frames_for(state.capacity, state.page_size);
+        state.rebuild(num_frames, self.replacement);
+        result
+    }
+
+    /// Pins page `page_id` of `file_id` for reading. A page can only be
+    /// pinned once at a time; pinning it again returns
+    /// `Error::PageBusy`.
+    pub fn pin(&self, file_id: usize, page_id: usize) -> Result<PageGuard<'_>> {
+        let page = self.check_out((file_id, page_id), true)?;
+        Ok(PageGuard { pool: self, file_id, page: Some(page) })
+    }
+
+    /// Pins page `page_id` of `file_id` for writing. The page is
+    /// marked dirty once it is changed through the guard. See `pin`.
+    pub fn pin_mut(&self, file_id: usize, page_id: usize) -> Result<PageGuardMut<'_>> {
+        let page = self.check_out((file_id, page_id), true)?;
+        Ok(PageGuardMut { pool: self, file_id, page: Some(page) })
+    }
+
+    /// Pins page `page_id` of `file_id` for writing as an empty page,
+    /// without reading what the file holds there. For pages that are
+    /// not in use yet, which the file may have garbage for after a
+    /// crash. If the page is buffered it is returned as it is.
+    pub fn pin_new(&self, file_id: usize, page_id: usize) -> Result<PageGuardMut<'_>> {
+        let page = self.check_out((file_id, page_id), false)?;
+        Ok(PageGuardMut { pool: self, file_id, page: Some(page) })
+    }
+
+    /// Takes the page with `key` out of its frame, reading it in if
+    /// it is not buffered, or starting it empty and dirty unless
+    /// `read`. The frame cannot be evicted until the page is put back
+    /// by `check_in`.
+    fn check_out(&self, key: PageKey, read: bool) -> Result<Page> {
+        let mut state = self.lock();
+        let state = &mut *state;
+        if let Some(&frame) = state.page_table.get(&key) {
+            state.stats.hits += 1;
+            state.policy.touched(frame);
+            return state.frames[frame].page.take().ok_or(Error::PageBusy { page_id: key.1 });
+        }
+        state.stats.misses += 1;
+
+        let mut page = Page::new(state.page_size);
+        page.id = key.1;
+        if read {
+            let pool_file = state.file(key.0);
+            let written = pool_file.read_page(key.1, &mut page.storage)?;
+            if pool_file.verify_checksums && written && !page.checksum_valid() {
+                return Err(Error::Corrupt { page_id: key.1, reason: "page checksum mismatch" });
+            }
+            page.read_header();
+        } else {
+            page.dirty = true;
+        }
+
+        let frame = match state.free_frames.pop() {
+            Some(frame) => frame,
+            None => {
+                let frame = {
+                    let frames = &state.frames;
+                    state.policy.victim(&|frame| frames[frame].page.is_none())
+                };
+                let frame = frame.ok_or(Error::PoolExhausted)?;
+                state.evict(frame)?;
+                frame
+            },
+        };
+        state.frames[frame].key = Some(key);
+        state.page_table.insert(key, frame);
+        state.policy.loaded(frame, key);
+        Ok(page)
+    }
+
+    fn check_in(&self, file_id: usize, page: Page) {
+        let mut state = self.lock();
+        if page.dirty {
+            state.file_mut(file_id).dirty.insert(page.id);
+        }
+        let frame = state.page_table[&(file_id, page.id)];
+        state.frames[frame].page = Some(page);
+    }
+
+    /// Stores `ctrl`, the control page of `file_id`, after writing
+    /// back the dirty pages of the file, which it may refer to. With a
+    /// log or shadow paging, they are committed along with it and are
+    /// on disk when this returns. Otherwise they are written to the
+    /// file in place, without waiting for the disk.
+    ///
+    /// Right before `ctrl` is written, `seal` is called with it and the
+    /// root of the page map with shadow paging, or 0. It returns the
+    /// bytes of `ctrl` it filled in, and only those are written over
+    /// the control page in the file.
+    pub fn commit<F>(&self, file_id: usize, ctrl: &mut [u8], seal: F) -> Result<()>
+        where F: FnOnce(&mut [u8], usize) -> Range<usize>
+    {
+        let mut state = self.lock();
+        state.write_dirty(file_id)?;
+        state.file_mut(file_id).commit(ctrl, seal)?;
+        Ok(())
+    }
+
+    /// Writes the dirty pages of `file_id` and then its control page
+    /// `ctrl` to the file, and waits until they are on disk. With a
+    /// log, they are committed to the log first and the log is
+    /// emptied afterwards. `seal` is called as in `commit`.
+    pub fn checkpoint<F>(&self, file_id: usize, ctrl: &mut [u8], seal: F) -> Result<()>
+        where F: FnOnce(&mut [u8], usize) -> Range<usize>
+    {
+        let mut state = self.lock();
+        state.write_dirty(file_id)?;
+        let pool_file = state.file_mut(file_id);
+        let sealed = pool_file.commit(ctrl, seal)?;
+        match pool_file.journal {
+            Journal::InPlace => pool_file.file.sync_all()?,
+            Journal::Wal(ref mut wal) => wal.checkpoint(&pool_file.file, Some(sealed))?,
+            // Committed pages are on disk already
+            Journal::Shadow(_) => (),
+        }
+        Ok(())
+    }
+
+    /// Reads page `page_id` of `file_id` into `buf` without buffering
+    /// it.
+    pub fn read_uncached(&self, file_id: usize, page_id: usize, buf: &mut [u8]) -> Result<()> {
+        self.lock().file(file_id).read_page(page_id, buf)?;
+        Ok(())
+    }
+
+    /// Has the log of `file_id` grown large enough that it should be
+    /// committed, so that it can be checkpointed? See `Wal::is_full`.
+    pub fn log_full(&self, file_id: usize) -> bool {
+        match self.lock().file(file_id).journal {
+            Journal::Wal(ref wal) => wal.is_full(),
+            _ => false,
+        }
+    }
+
+    pub fn file_len(&self, file_id: usize) -> Result<u64> {
+        Ok(self.lock().file(file_id).file.metadata()?.len())
+    }
+}
+
+/// A page pinned for reading, see `BufferPool::pin`.
+pub struct PageGuard<'a> {
+    pool: &'a BufferPool,
+    file_id: usize,
+    // only None once dropped
+    page: Option<Page>,
+}
+
+impl<'a> Deref for PageGuard<'a> {
+    type Target = Page;
+
+    fn deref(&self) -> &Page {
+        self.page.as_ref().expect("page guard was dropped")
+    }
+}
+
+impl<'a> Drop for PageGuard<'a> {
+    fn drop(&mut self) {
+        if let Some(page) = self.page.take() {
+            self.pool.check_in(self.file_id, page);
+        }
+    }
+}
+
+/// A page pinned for writing, see `BufferPool::pin_mut`.
+pub struct PageGuardMut<'a> {
+    pool: &'a BufferPool,
+    file_id: usize,
+    // only None once dropped
+    page: Option<Page>,
+}
+
+impl<'a> Deref for PageGuardMut<'a> {
+    type Target = Page;
+
+    fn deref(&self) -> &Page {
+        self.page.as_ref().expect("page guard was dropped")
+    }
+}
+
+impl<'a> DerefMut for PageGuardMut<'a> {
+    fn deref_mut(&mut self) -> &mut Page {
+        let page = self.page.as_mut().expect("page guard was dropped");
+        page.dirty = true;
+        page
+    }
+}
+
+impl<'a> Drop for PageGuardMut<'a> {
+    fn drop(&mut self) {
+        if let Some(page) = self.page.take() {
+            self.pool.check_in(self.file_id, page);
+        }
+    }
+}
+
+const NIL: usize = usize::MAX;
+
+/// Doubly linked list of frames, most recently added at the front.
+struct FrameList {
+    prev: Vec<usize>,
+    next: Vec<usize>,
+    head: usize,
+    tail: usize,
+    len: usize,
+}
+
+impl FrameList {
+    fn new(num_frames: usize) -> FrameList {
+        FrameList {
+            prev: vec![NIL; num_frames],
+            next: vec![NIL; num_frames],
+            head: NIL,
+            tail: NIL,
+            len: 0,
+        }
+    }
+
+    fn push_front(&mut self, frame: usize) {
+        self.prev[frame] = NIL;
+        self.next[frame] = self.head;
+        if self.head != NIL {
+            self.prev[self.head] = frame;
+        } else {
+            self.tail = frame;
+        }
+        self.head = frame;
+        self.len += 1;
+    }
+
+    fn remove(&mut self, frame: usize) {
+        let (prev, next) = (self.prev[frame], self.next[frame]);
+        if prev != NIL {
+            self.next[prev] = next;
+        } else {
+            self.head = next;
+        }
+        if next != NIL {
+            self.prev[next] = prev;
+        } else {
+            self.tail = prev;
+        }
+        self.len -= 1;
+    }
+
+    /// Removes and returns the frame nearest the back for which
+    /// `pinned` is false.
+    fn pop_back_unpinned(&mut self, pinned: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let mut frame = self.tail;
+        while frame != NIL && pinned(frame) {
+            frame = self.prev[frame];
+        }
+        if frame == NIL {
+            return None;
+        }
+        self.remove(frame);
+        Some(frame)
+    }
+}
+
+pub struct Lru {
+    frames: FrameList,
+}
+
+impl Lru {
+    pub fn new(num_frames: usize) -> Lru {
+        Lru { frames: FrameList::new(num_frames) }
+    }
+}
+
+impl ReplacementPolicy for Lru {
+    fn loaded(&mut self, frame: usize, _key: PageKey) {
+        self.frames.push_front(frame);
+    }
+
+    fn touched(&mut self, frame: usize) {
+        self.frames.remove(frame);
+        self.frames.push_front(frame);
+    }
+
+    fn removed(&mut self, frame: usize) {
+        self.frames.remove(frame);
+    }
+
+    fn victim(&mut self, pinned: &dyn Fn(usize) -> bool) -> Option<usize> {
+        self.frames.pop_back_unpinned(pinned)
+    }
+}
+
+pub struct Clock {
+    referenced: Vec<bool>,
+    hand: usize,
+}
+
+impl Clock {
+    pub fn new(num_frames: usize) -> Clock {
+        Clock { referenced: vec![false; num_frames], hand: 0 }
+    }
+}
+
+impl ReplacementPolicy for Clock {
+    fn loaded(&mut self, frame: usize, _key: PageKey) {
+        self.referenced[frame] = true;
+    }
+
+    fn touched(&mut self, frame: usize) {
+        self.referenced[frame] = true;
+    }
+
+    // Unused frames are skipped by `victim` as if they were pinned
+    fn removed(&mut self, frame: usize) {
+        self.referenced[frame] = false;
+    }
+
+    fn victim(&mut self, pinned: &dyn Fn(usize) -> bool) -> Option<usize> {
+        // After one sweep every unpinned frame is unreferenced
+        for _ in 0..2 * self.referenced.len() {
+            let frame = self.hand;
+            self.hand = (self.hand + 1) % self.referenced.len();
+            if pinned(frame) {
+                continue;
+            }
+            if !self.referenced[frame] {
+                return Some(frame);
+            }
+            self.referenced[frame] = false;
+        }
+        None
+    }
+}
+
+/// The simplified 2Q of Johnson and Shasha. New pages go to a FIFO
+/// queue, `a1in`, and only pages used again after being evicted from
+/// it are kept in the LRU queue `am`. Evicted pages are remembered in
+/// `a1out`.
+pub struct TwoQ {
+    a1in: FrameList,
+    am: FrameList,
+    in_am: Vec<bool>,
+    keys: Vec<PageKey>,
+    a1out: VecDeque<PageKey>,
+    a1out_set: HashSet<PageKey>,
+    // target sizes of `a1in` and `a1out`
+    kin: usize,
+    kout: usize,
+}
+
+impl TwoQ {
+    pub fn new(num_frames: usize) -> TwoQ {
+        TwoQ {
+            a1in: FrameList::new(num_frames),
+            am: FrameList::new(num_frames),
+            in_am: vec![false; num_frames],
+            keys: vec![(0, 0); num_frames],
+            a1out: VecDeque::new(),
+            a1out_set: HashSet::new(),
+            kin: (num_frames / 4).max(1),
+            kout: (num_frames / 2).max(1),
+        }
+    }
+
+    fn evict_a1in(&mut self, pinned: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let frame = self.a1in.pop_back_unpinned(pinned)?;
+        let key = self.keys[frame];
+        self.remember_evicted(key);
+        Some(frame)
+    }
+
+    fn remember_evicted(&mut self, key: PageKey) {
+        if self.a1out.len() == self.kout {
+            if let Some(oldest) = self.a1out.pop_front() {
+                self.a1out_set.remove(&oldest);
+            }
+        }
+        self.a1out.push_back(key);
+        self.a1out_set.insert(key);
+    }
+}
+
+impl ReplacementPolicy for TwoQ {
+    fn loaded(&mut self, frame: usize, key: PageKey) {
+        self.keys[frame] = key;
+        if self.a1out_set.remove(&key) {
+            self.a1out.retain(|&k| k != key);
+            self.in_am[frame] = true;
+            self.am.push_front(frame);
+        } else {
+            self.in_am[frame] = false;
+            self.a1in.push_front(frame);
+        }
+    }
+
+    fn touched(&mut self, frame: usize) {
+        // Pages in `a1in` are only promoted once evicted and read
+        // again, so that one burst of use does not keep them
+        if self.in_am[frame] {
+            self.am.remove(frame);
+            self.am.push_front(frame);
+        }
+    }
+
+    fn removed(&mut self, frame: usize) {
+        if self.in_am[frame] {
+            self.am.remove(frame);
+        } else {
+            self.a1in.remove(frame);
+        }
+    }
+
+    fn victim(&mut self, pinned: &dyn Fn(usize) -> bool) -> Option<usize> {
+        if self.a1in.len > self.kin || self.am.len == 0 {
+            if let Some(frame) = self.evict_a1in(pinned) {
+                return Some(frame);
+            }
+        }
+        self.am.pop_back_unpinned(pinned).or_else(|| self.evict_a1in(pinned))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use error::Error;
+    use pool::*;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    fn test_file(filename: &str) -> File {
+        OpenOptions::new().read(true).write(true).create(true)
+            .truncate(true).open(filename).unwrap()
+    }
+
+    // Fills all frames with pages 0..n, then replays `accesses`,
+    // returning the pages that were evicted.
+    fn evictions(replacement: Replacement, n: usize, accesses: &[usize]) -> Vec<usize> {
+        let mut policy = replacement.policy(n);
+        let mut frames: Vec<usize> = (0..n).collect();
+        for frame in 0..n {
+            policy.loaded(frame, (0, frame));
+        }
+        let mut evicted = vec![];
+        for &page_id in accesses {
+            match frames.iter().position(|&p| p == page_id) {
+                Some(frame) => policy.touched(frame),
+                None => {
+                    let frame = policy.victim(&|_| false).unwrap();
+                    evicted.push(frames[frame]);
+                    frames[frame] = page_id;
+                    policy.loaded(frame, (0, page_id));
+                },
+            }
+        }
+        evicted
+    }
+
+    #[test]
+    fn lru() {
+        assert_eq!(evictions(Replacement::Lru, 3, &[0, 3, 1, 4, 0]), vec![1, 2, 0, 3]);
+    }
+
+    #[test]
+    fn clock() {
+        // All pages start out referenced, so the first miss clears
+        // every bit and evicts page 0; the next ones find 1 and 2
+        // unreferenced
+        assert_eq!(evictions(Replacement::Clock, 3, &[3, 0, 4, 5]), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn two_q() {
+        // Page 0 is evicted and read again, so it survives a scan
+        // over pages 10.., which LRU would let push it out
+        let mut accesses = vec![8, 9, 0];
+        accesses.extend(10..20);
+        let evicted = evictions(Replacement::TwoQ, 8, &accesses);
+        assert_eq!(&evicted[..3], &[0, 1, 2]);
+        assert!(!evicted[3..].contains(&0));
+        assert!(evictions(Replacement::Lru, 8, &accesses)[3..].contains(&0));
+    }
+
+    #[test]
+    fn page_table() {
+        let pool = BufferPool::new(512, 8 * 512, Replacement::Lru).unwrap();
+        let file_id = pool.register(test_file("/tmp/page_table"), Journal::InPlace);
+        for page_id in (1..100).chain(50..60).chain(1..10) {
+            assert_eq!(pool.pin(file_id, page_id).unwrap().id, page_id);
+            let state = pool.lock();
+            assert!(state.page_table.len() <= 8);
+            for (&key, &frame) in state.page_table.iter() {
+                assert_eq!(state.frames[frame].key, Some(key));
+                assert_eq!(state.frames[frame].page.as_ref().unwrap().id, key.1);
+            }
+        }
+        fs::remove_file("/tmp/page_table").ok();
+    }
+
+    #[test]
+    fn reads_past_end() {
+        let mut file = test_file("/tmp/reads_past_end");
+        file.write_all(&[0xab; 512 + 100]).unwrap();
+        let pool = BufferPool::new(512, 8 * 512, Replacement::Lru).unwrap();
+        let file_id = pool.register(file, Journal::InPlace);
+        pool.set_verify_checksums(file_id, false);
+
+        // Page 1 is cut short by the end of the file, page 2 was never
+        // written
+        match pool.pin(file_id, 1) {
+            Err(Error::Corrupt { page_id: 1, .. }) => (),
+            _ => panic!("expected a page cut short to be corrupt"),
+        }
+        let page = pool.pin(file_id, 2).unwrap();
+        assert!(page.storage.iter().all(|&b| b == 0));
+        assert_eq!(page.num_records, 0);
+        fs::remove_file("/tmp/reads_past_end").ok();
+    }
+
+    #[test]
+    fn page_sizes() {
+        for &page_size in &[0, 64, 1000, 65536] {
+            match BufferPool::new(page_size, 8 * 512, Replacement::Lru) {
+                Err(Error::InvalidOption { option: "page_size", .. }) => (),
+                _ => panic!("expected page size {} to be rejected", page_size),
+            }
+        }
+        assert!(BufferPool::new(512, 8 * 512, Replacement::Lru).is_ok());
+    }
+
+    #[test]
+    fn pinned_pages_stay() {
+        for &replacement in &[Replacement::Lru, Replacement::Clock, Replacement::TwoQ] {
+            let pool = BufferPool::new(512, 4 * 512, replacement).unwrap();
+            let file_id = pool.register(test_file("/tmp/pinned_pages_stay"), Journal::InPlace);
+            let mut first = pool.pin_mut(file_id, 1).unwrap();
+            first.insert_record(b"key", b"val");
+            for page_id in 2..50 {
+                pool.pin(file_id, page_id).unwrap();
+            }
+            assert_eq!(first.read_record(0), (&b"key"[..], &b"val"[..]));
+            drop(first);
+
+            let guards: Vec<_> = (1..5).map(|page_id| pool.pin(file_id, page_id).unwrap()).collect();
+            match pool.pin(file_id, 5) {
+                Err(Error::PoolExhausted) => (),
+                _ => panic!("expected PoolExhausted with every frame pinned"),
+            }
+            match pool.pin(file_id, 1) {
+                Err(Error::PageBusy { page_id: 1 }) => (),
+                _ => panic!("expected PageBusy for a page pinned twice"),
+            }
+            drop(guards);
+            pool.pin(file_id, 5).unwrap();
+            assert_eq!(pool.pin(file_id, 1).unwrap().read_record(0), (&b"key"[..], &b"val"[..]));
+        }
+        fs::remove_file("/tmp/pinned_pages_stay").ok();
+    }
+
+    #[test]
+    fn shared_files() {
+        let pool = BufferPool::new(512, 4 * 512, Replacement::TwoQ).unwrap();
+        let a = pool.register(test_file("/tmp/shared_files_a"), Journal::InPlace);
+        let b = pool.register(test_file("/tmp/shared_files_b"), Journal::InPlace);
+        for page_id in 1..20 {
+            pool.pin_mut(a, page_id).unwrap().insert_record(b"a", &[page_id as u8]);
+            pool.pin_mut(b, page_id).unwrap().insert_record(b"b", &[page_id as u8]);
+        }
+        for page_id in 1..20 {
+            assert_eq!(pool.pin(a, page_id).unwrap().read_record(0), (&b"a"[..], &[page_id as u8][..]));
+            assert_eq!(pool.pin(b, page_id).unwrap().read_record(0), (&b"b"[..], &[page_id as u8][..]));
+        }
+
+        // Dropping a file frees its frames for the other one
+        pool.unregister(a);
+        for page_id in 1..5 {
+            pool.pin(b, page_id).unwrap();
+        }
+        let state = pool.lock();
+        assert!(state.page_table.keys().all(|key| key.0 == b));
+        drop(state);
+
+        // Shrinking to the minimum keeps the pages readable
+        pool.set_capacity(0).unwrap();
+        assert_eq!(pool.lock().frames.len(), 2);
+        for page_id in 1..20 {
+            assert_eq!(pool.pin(b, page_id).unwrap().read_record(0), (&b"b"[..], &[page_id as u8][..]));
+        }
+        fs::remove_file("/tmp/shared_files_a").ok();
+        fs::remove_file("/tmp/shared_files_b").ok();
+    }
+
+    #[test]
+    fn commit_writes_dirty_pages() {
+        let pool = BufferPool::new(512, 8 * 512, Replacement::Lru).unwrap();
+        let file_id = pool.register(test_file("/tmp/commit_writes_dirty_pages"), Journal::InPlace);
+        for page_id in 1..6 {
+            pool.pin(file_id, page_id).unwrap();
+        }
+        pool.pin_mut(file_id, 4).unwrap().insert_record(b"k", b"4");
+        let mut pinned = pool.pin_mut(file_id, 2).unwrap();
+        pinned.insert_record(b"k", b"2");
+        assert_eq!(pool.lock().file(file_id).dirty.iter().collect::<Vec<_>>(), vec![&4]);
+
+        // Only the dirty page that is not pinned is written back
+        let mut ctrl = vec![0; 512];
+        pool.commit(file_id, &mut ctrl, |_, _| 0..0).unwrap();
+        assert!(pool.lock().file(file_id).dirty.is_empty());
+        drop(pinned);
+        assert_eq!(pool.lock().file(file_id).dirty.iter().collect::<Vec<_>>(), vec![&2]);
+        pool.commit(file_id, &mut ctrl, |_, _| 0..0).unwrap();
+        assert!(pool.lock().file(file_id).dirty.is_empty());
+
+        let mut buf = vec![0; 512];
+        for &page_id in &[2, 4] {
+            read_page(&pool.lock().file(file_id).file, page_id, &mut buf).unwrap();
+            let mut page = Page::new(512);
+            page.storage.copy_from_slice(&buf);
+            page.read_header();
+            assert_eq!(page.read_record(0).1, page_id.to_string().as_bytes());
+        }
+        fs::remove_file("/tmp/commit_writes_dirty_pages").ok();
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fs::File;
+use std::ops::Range;
+
+use error::{Error, Result};
+use page::{Page, HEADER_SIZE};
+use pool::{read_page, write_ctrl, write_page};
+use util::*;
+
+/// Shadow paging: pages are never written over where the last
+/// committed version of the table has them. The table's page ids are
+/// logical, and a map gives the physical page each one is stored in.
+/// A changed page is written to a free physical page, and a commit
+/// writes the changed parts of the map to free pages too before the
+/// control page is switched to its new root. Until then, the control
+/// page points to the map of the previous commit, all of whose pages
+/// are intact, so after a crash the table is as it was at the last
+/// commit.
+///
+/// The map is split into map pages of `entries_per_page` entries, and
+/// a chain of index pages starting at the map root gives the physical
+/// page of each map page. A commit writes only the map pages whose
+/// entries changed, and then the index, which is small. Both kinds of
+/// page have the header and checksum of a table page, see `Page`, with
+/// `num_records` entries of 8 bytes after the header.
+///
+/// Physical pages of the previous version are reused once the next
+/// commit no longer needs them. The physical page 0 is the control
+/// page, which is written in place.
+pub struct Shadow {
+    page_size: usize,
+    // physical page of each logical page, 0 if it has none yet
+    map: Vec<usize>,
+    // physical page each map page was last committed to, 0 if it
+    // was not committed yet
+    map_pages: Vec<usize>,
+    // map pages whose entries changed since the last commit
+    changed: BTreeSet<usize>,
+    // physical pages the index was last committed to
+    index_pages: Vec<usize>,
+    // physical pages of the last committed version
+    live: HashSet<usize>,
+    // physical pages of the last committed version that the next
+    // commit no longer uses
+    released: Vec<usize>,
+    free: BTreeSet<usize>,
+    // physical pages in the file
+    num_pages: usize,
+    // were pages written since the last commit?
+    unsynced: bool,
+}
+
+fn entries_per_page(page_size: usize) -> usize {
+    (page_size - HEADER_SIZE) / 8
+}
+
+/// Reads map or index page `page_id`, returning its entries and the
+/// next page in its chain, 0 if there is none.
+fn read_map_page(file: &File, page_id: usize, page_size: usize,
+                 file_len: usize) -> Result<(Vec<usize>, usize)> {
+    if page_id == 0 || (page_id + 1) * page_size > file_len {
+        return Err(Error::Corrupt { page_id, reason: "invalid page map" });
+    }
+    let mut page = Page::new(page_size);
+    page.id = page_id;
+    read_page(file, page_id, &mut page.storage)?;
+    if !page.checksum_valid() {
+        return Err(Error::Corrupt { page_id, reason: "page map checksum mismatch" });
+    }
+    page.read_header();
+    if page.num_records > entries_per_page(page_size) {
+        return Err(Error::Corrupt { page_id, reason: "invalid page map" });
+    }
+    let entries = bytevec_to_usize_vec(
+        page.storage[HEADER_SIZE..HEADER_SIZE + page.num_records * 8].to_vec());
+    Ok((entries, page.next.unwrap_or(0)))
+}
+
+fn write_map_page(file: &File, page_id: usize, page_size: usize,
+                  entries: &[usize], next: usize) -> Result<()> {
+    let mut page = Page::new(page_size);
+    page.id = page_id;
+    page.num_records = entries.len();
+    page.next = if next != 0 { Some(next) } else { None };
+    mem_move(&mut page.storage[HEADER_SIZE..HEADER_SIZE + entries.len() * 8],
+             &usize_vec_to_bytevec(entries.to_vec()));
+    page.write_header();
+    write_page(file, page_id, &page.storage)
+}
+
+impl Shadow {
+    /// Loads the map of `file` starting at page `root`, 0 if the file
+    /// has none yet.
+    pub fn open(file: &File, page_size: usize, root: usize) -> Result<Shadow> {
+        let mut shadow = Shadow {
+            page_size,
+            map: vec![],
+            map_pages: vec![],
+            changed: BTreeSet::new(),
+            index_pages: vec![],
+            live: HashSet::new(),
+            released: vec![],
+            free: BTreeSet::new(),
+            num_pages: 1,
+            unsynced: false,
+        };
+        let file_len = file.metadata()?.len() as usize;
+        if file_len == 0 {
+            return Ok(shadow);
+        }
+
+        let mut next_page = root;
+        while next_page != 0 {
+            if shadow.index_pages.contains(&next_page) {
+                return Err(Error::Corrupt { page_id: next_page, reason: "invalid page map" });
+            }
+            let (map_pages, next) = read_map_page(file, next_page, page_size, file_len)?;
+            shadow.map_pages.extend(map_pages);
+            shadow.index_pages.push(next_page);
+            next_page = next;
+        }
+        let entries_per_page = entries_per_page(page_size);
+        for (i, &page_id) in shadow.map_pages.iter().enumerate() {
+            let (entries, _) = read_map_page(file, page_id, page_size, file_len)?;
+            // Only the last map page may be partly filled
+            if entries.len() != entries_per_page && i + 1 != shadow.map_pages.len() {
+                return Err(Error::Corrupt { page_id, reason: "invalid page map" });
+            }
+            shadow.map.extend(entries);
+        }
+
+        shadow.live = shadow.map.iter().cloned().filter(|&p| p != 0)
+            .chain(shadow.map_pages.iter().cloned())
+            .chain(shadow.index_pages.iter().cloned()).collect();
+        let max_live = shadow.live.iter().cloned().max().unwrap_or(0);
+        shadow.num_pages = (file_len / page_size).max(max_live + 1);
+        let live = &shadow.live;
+        shadow.free = (1..shadow.num_pages).filter(|p| !live.contains(p)).collect();
+        Ok(shadow)
+    }
+
+    fn allocate(&mut self) -> usize {
+        match self.free.iter().next().cloned() {
+            Some(page_id) => {
+                self.free.remove(&page_id);
+                page_id
+            },
+            None => {
+                self.num_pages += 1;
+                self.num_pages - 1
+            },
+        }
+    }
+
+    /// Reads logical page `page_id` into `buf`. A page that was never
+    /// written is all zeroes, and false is returned for it.
+    pub fn read_page(&self, file: &File, page_id: usize, buf: &mut [u8]) -> Result<bool> {
+        match self.map.get(page_id) {
+            Some(&physical) if physical != 0 => read_page(file, physical, buf),
+            _ => {
+                for b in buf.iter_mut() {
+                    *b = 0;
+                }
+                Ok(false)
+            },
+        }
+    }
+
+    /// Writes logical page `page_id`, moving it to a free physical
+    /// page if the committed version of the table still uses the one
+    /// it is in.
+    pub fn write_page(&mut self, file: &File, page_id: usize, data: &[u8]) -> Result<()> {
+        let entries_per_page = entries_per_page(self.page_size);
+        if self.map.len() <= page_id {
+            // The last map page and any new ones get more entries
+            self.changed.extend(self.map.len() / entries_per_page..=page_id / entries_per_page);
+            self.map.resize(page_id + 1, 0);
+        }
+        let current = self.map[page_id];
+        let physical = if current != 0 && !self.live.contains(&current) {
+            current
+        } else {
+            if current != 0 {
+                self.released.push(current);
+            }
+            let physical = self.allocate();
+            self.map[page_id] = physical;
+            self.changed.insert(page_id / entries_per_page);
+            physical
+        };
+        self.unsynced = true;
+        write_page(file, physical, data)
+    }
+
+    /// Makes everything written so far the committed version: writes
+    /// the changed map pages and the index to free pages and waits for
+    /// the file to be on disk. Then `seal` is given `ctrl` and the new
+    /// map root to store in it, and the bytes of `ctrl` it returns are
+    /// written over page 0.
+    pub fn commit<F>(&mut self, file: &File, ctrl: &mut [u8], seal: F) -> Result<Range<usize>>
+        where F: FnOnce(&mut [u8], usize) -> Range<usize>
+    {
+        let (page_size, entries_per_page) = (self.page_size, entries_per_page(self.page_size));
+        let mut written = vec![];
+        if !self.changed.is_empty() {
+            self.map_pages.resize(self.map.len().div_ceil(entries_per_page), 0);
+            let changed: Vec<usize> = self.changed.iter().cloned().collect();
+            for i in changed {
+                let old = self.map_pages[i];
+                if old != 0 {
+                    self.released.push(old);
+                }
+                let page_id = self.allocate();
+                let entries = &self.map[i * entries_per_page..
+                                        ((i + 1) * entries_per_page).min(self.map.len())];
+                write_map_page(file, page_id, page_size, entries, 0)?;
+                written.extend(entries.iter().cloned().filter(|&p| p != 0));
+                written.push(page_id);
+                self.map_pages[i] = page_id;
+            }
+
+            self.released.extend(self.index_pages.iter().cloned());
+            let index_pages: Vec<usize> = (0..self.map_pages.len().div_ceil(entries_per_page))
+                .map(|_| self.allocate()).collect();
+            for (i, &page_id) in index_pages.iter().enumerate() {
+                let entries = &self.map_pages[i * entries_per_page..
+                                              ((i + 1) * entries_per_page).min(self.map_pages.len())];
+                let next = index_pages.get(i + 1).cloned().unwrap_or(0);
+                write_map_page(file, page_id, page_size, entries, next)?;
+            }
+            written.extend(index_pages.iter().cloned());
+            self.index_pages = index_pages;
+            self.unsynced = true;
+        }
+        if self.unsynced {
+            file.sync_all()?;
+        }
+
+        let sealed = seal(ctrl, self.index_pages.first().cloned().unwrap_or(0));
+        write_ctrl(file, ctrl, sealed.clone())?;
+        file.sync_all()?;
+
+        self.changed.clear();
+        self.unsynced = false;
+        for page_id in self.released.drain(..) {
+            self.live.remove(&page_id);
+            self.free.insert(page_id);
+        }
+        self.live.extend(written);
+        Ok(sealed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, OpenOptions};
+
+    #[test]
+    fn commits_changed_map_pages() {
+        let path = "/tmp/commits_changed_map_pages";
+        let file = OpenOptions::new().read(true).write(true).create(true)
+            .truncate(true).open(path).unwrap();
+        let seal = |_: &mut [u8], _: usize| 0..512;
+        let (mut ctrl, data) = (vec![0; 512], vec![7; 512]);
+        let mut shadow = Shadow::open(&file, 512, 0).unwrap();
+        for page_id in 1..500 {
+            shadow.write_page(&file, page_id, &data).unwrap();
+        }
+        shadow.commit(&file, &mut ctrl, seal).unwrap();
+        let (map_pages, index_pages) = (shadow.map_pages.clone(), shadow.index_pages.clone());
+        assert_eq!((map_pages.len(), index_pages.len()), (9, 1));
+
+        // Only the map page of the changed page and the index move
+        shadow.write_page(&file, 70, &data).unwrap();
+        shadow.commit(&file, &mut ctrl, seal).unwrap();
+        for (i, (&new, &old)) in shadow.map_pages.iter().zip(&map_pages).enumerate() {
+            assert_eq!(new == old, i != 1);
+        }
+        assert_ne!(shadow.index_pages, index_pages);
+        let root = shadow.index_pages[0];
+        let reopened = Shadow::open(&file, 512, root).unwrap();
+        assert_eq!(reopened.map, shadow.map);
+
+        // Nothing changed, nothing moves
+        shadow.commit(&file, &mut ctrl, seal).unwrap();
+        assert_eq!(shadow.index_pages[0], root);
+
+        // A damaged map page is found
+        let mut page = vec![0; 512];
+        read_page(&file, shadow.map_pages[3], &mut page).unwrap();
+        page[100] ^= 1;
+        write_page(&file, shadow.map_pages[3], &page).unwrap();
+        match Shadow::open(&file, 512, root) {
+            Err(Error::Corrupt { .. }) => (),
+            _ => panic!("expected a damaged map page to be corrupt"),
+        }
+        fs::remove_file(path).ok();
+    }
+}
@@ -1,5 +1,3 @@
-use std::mem::transmute;
-
 pub fn mem_move(dest: &mut [u8], src: &[u8]) {
     for (d, s) in dest.iter_mut().zip(src) {
         *d = *s
@@ -7,15 +5,22 @@ pub fn mem_move(dest: &mut [u8], src: &[u8]) {
 }
 
 pub fn usize_to_bytearray(n: usize) -> [u8; 8] {
-    unsafe {
-        transmute::<usize, [u8;8]>(n)
-    }
+    n.to_ne_bytes()
+}
+
+pub fn u16_to_bytearray(n: u16) -> [u8; 2] {
+    n.to_ne_bytes()
+}
+
+pub fn bytearray_to_u16(b: &[u8]) -> u16 {
+    let mut a = [0; 2];
+    a.copy_from_slice(b);
+
+    u16::from_ne_bytes(a)
 }
 
 pub fn i32_to_bytearray(n: i32) -> [u8; 4] {
-    unsafe {
-        transmute::<i32, [u8;4]>(n)
-    }
+    n.to_ne_bytes()
 }
 
 pub fn usize_vec_to_bytevec(v: Vec<usize>) -> Vec<u8> {
@@ -38,16 +43,11 @@ pub fn bytevec_to_usize_vec(b: Vec<u8>) -> Vec<usize> {
 pub fn bytearray_to_usize(b: Vec<u8>) -> usize {
     assert_eq!(b.len(), 8);
     let mut a = [0; 8];
+    a.copy_from_slice(&b);
 
-    for i in 0..b.len() {
-        a[i] = b[i];
-    }
-
-    unsafe {
-        transmute::<[u8;8], usize>(a)
-    }
+    usize::from_ne_bytes(a)
 }
 
 pub fn slices_eq<T: PartialEq>(s1: &[T], s2: &[T]) -> bool {
-    s1.iter().zip(s2).all(|(a,b)| a == b)
+    s1.len() == s2.len() && s1.iter().zip(s2).all(|(a,b)| a == b)
 }
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+
+use error::{Error, Result};
+use hash::{KeyHasher, XxHash64};
+use page::Page;
+use pool::{write_ctrl, write_page};
+use util::*;
+
+// Record kinds
+const PAGE_RECORD : usize = 1;
+const COMMIT_RECORD : usize = 2;
+
+// Every record starts with three 8 byte fields:
+//
+// page record:   | PAGE_RECORD | page id | length | page (length bytes) |
+// commit record: | COMMIT_RECORD | records since last commit | checksum |
+const RECORD_HEADER_SIZE : usize = 24;
+
+/// Bytes a log may grow to before it is checkpointed, see `is_full`.
+pub const CHECKPOINT_LEN : u64 = 4 * 1024 * 1024;
+
+/// Write-ahead log of a table, kept in a file next to it. Pages are
+/// written back to the log instead of the table, and reads find the
+/// latest image of a page in the log before looking in the table. A
+/// commit record makes everything logged before it part of the table;
+/// a checkpoint copies the committed pages into the table and empties
+/// the log.
+///
+/// After a crash, pages logged after the last commit are ignored, so
+/// the table is exactly as it was at that commit.
+pub struct Wal {
+    file: File,
+    // end of the log, where the next record goes
+    len: u64,
+    // offset of the latest image of each page in the log
+    index: HashMap<usize, u64>,
+    // page records since the last commit, and their checksum
+    pending: usize,
+    checksum: u64,
+}
+
+fn record_header(kind: usize, a: usize, b: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(RECORD_HEADER_SIZE);
+    header.extend_from_slice(&usize_to_bytearray(kind));
+    header.extend_from_slice(&usize_to_bytearray(a));
+    header.extend_from_slice(&usize_to_bytearray(b));
+    header
+}
+
+fn next_checksum(checksum: u64, record: &[u8]) -> u64 {
+    checksum.rotate_left(1) ^ XxHash64.hash(record)
+}
+
+/// Reads `buf.len()` bytes at `offset`, returning false if the file
+/// ends first.
+fn read_at(file: &File, offset: u64, buf: &mut [u8]) -> Result<bool> {
+    match file.read_exact_at(buf, offset) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn cut_short(page_id: usize) -> Error {
+    Error::Corrupt { page_id, reason: "page cut short in write-ahead log" }
+}
+
+fn write_at(file: &File, offset: u64, data: &[u8]) -> Result<()> {
+    file.write_all_at(data, offset)?;
+    Ok(())
+}
+
+impl Wal {
+    /// Opens the log at `path`, creating it if needed. Whatever it
+    /// holds up to its last commit is first copied into `table`.
+    pub fn open(path: &str, table: &File) -> Result<Wal> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut wal = Wal {
+            file,
+            len: 0,
+            index: HashMap::new(),
+            pending: 0,
+            checksum: 0,
+        };
+        wal.replay()?;
+        wal.checkpoint(table, None)?;
+        Ok(wal)
+    }
+
+    /// Copies what the log at `path` holds up to its last commit into
+    /// `table`, if there is a log, and removes it.
+    pub fn recover(path: &str, table: &File) -> Result<()> {
+        if fs::metadata(path).is_err() {
+            return Ok(());
+        }
+        Wal::open(path, table)?;
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    /// Rebuilds `index` from the records up to the last valid commit
+    /// record. Anything after it was not committed, or is a torn
+    /// write, and is cut off.
+    fn replay(&mut self) -> Result<()> {
+        let file_len = self.file.metadata()?.len();
+        let mut offset = 0;
+        let mut committed = 0;
+        let mut pending = vec![];
+        let mut checksum = 0;
+        // every page logged has the size of the first one
+        let mut page_size = None;
+        let mut header = [0; RECORD_HEADER_SIZE];
+        while read_at(&self.file, offset, &mut header)? {
+            let field = |i: usize| bytearray_to_usize(header[i*8..(i+1)*8].to_vec());
+            let len = field(2);
+            let valid_len = Page::valid_size(len) && page_size.unwrap_or(len) == len &&
+                offset + (RECORD_HEADER_SIZE + len) as u64 <= file_len;
+            match field(0) {
+                PAGE_RECORD if valid_len => {
+                    page_size = Some(len);
+                    let mut record = header.to_vec();
+                    record.resize(RECORD_HEADER_SIZE + len, 0);
+                    if !read_at(&self.file, offset, &mut record)? {
+                        break;
+                    }
+                    checksum = next_checksum(checksum, &record);
+                    pending.push((field(1), offset));
+                    offset += record.len() as u64;
+                },
+                COMMIT_RECORD if field(1) == pending.len() && field(2) as u64 == checksum => {
+                    self.index.extend(pending.drain(..));
+                    checksum = 0;
+                    offset += RECORD_HEADER_SIZE as u64;
+                    committed = offset;
+                },
+                _ => break,
+            }
+        }
+        self.file.set_len(committed)?;
+        self.len = committed;
+        Ok(())
+    }
+
+    /// Reads the latest image of page `page_id` into `buf`. Returns
+    /// false if the page is not in the log.
+    pub fn read_page(&self, page_id: usize, buf: &mut [u8]) -> Result<bool> {
+        match self.index.get(&page_id) {
+            Some(&offset) => {
+                if !read_at(&self.file, offset + RECORD_HEADER_SIZE as u64, buf)? {
+                    return Err(cut_short(page_id));
+                }
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    /// Has the log grown past `CHECKPOINT_LEN`? It is only emptied by
+    /// a checkpoint, which needs everything in it to be committed.
+    pub fn is_full(&self) -> bool {
+        self.len >= CHECKPOINT_LEN
+    }
+
+    /// Appends an image of page `page_id`. It is part of the table
+    /// once `commit` is called.
+    pub fn append_page(&mut self, page_id: usize, data: &[u8]) -> Result<()> {
+        let mut record = record_header(PAGE_RECORD, page_id, data.len());
+        record.extend_from_slice(data);
+        write_at(&self.file, self.len, &record)?;
+        self.checksum = next_checksum(self.checksum, &record);
+        self.pending += 1;
+        self.index.insert(page_id, self.len);
+        self.len += record.len() as u64;
+        Ok(())
+    }
+
+    /// Appends a commit record and waits until the log is on disk.
+    pub fn commit(&mut self) -> Result<()> {
+        let record = record_header(COMMIT_RECORD, self.pending, self.checksum as usize);
+        write_at(&self.file, self.len, &record)?;
+        self.file.sync_data()?;
+        self.len += record.len() as u64;
+        self.pending = 0;
+        self.checksum = 0;
+        Ok(())
+    }
+
+    /// Copies the latest image of every page in the log into `table`,
+    /// then empties the log. Everything in the log must be committed.
+    ///
+    /// Of page 0, the control page, only the bytes in `ctrl` are
+    /// copied if given: the control page slot the last commit wrote,
+    /// so that a torn write cannot damage the other one.
+    pub fn checkpoint(&mut self, table: &File, ctrl: Option<Range<usize>>) -> Result<()> {
+        if self.index.is_empty() {
+            return Ok(());
+        }
+        // The index is kept until every page is copied, so a failed
+        // checkpoint can be retried
+        let mut pages: Vec<(usize, u64)> =
+            self.index.iter().map(|(&page_id, &offset)| (page_id, offset)).collect();
+        pages.sort();
+        let mut header = [0; RECORD_HEADER_SIZE];
+        for (page_id, offset) in pages {
+            if !read_at(&self.file, offset, &mut header)? {
+                return Err(cut_short(page_id));
+            }
+            let mut data = vec![0; bytearray_to_usize(header[16..24].to_vec())];
+            self.read_page(page_id, &mut data)?;
+            match ctrl {
+                Some(ref range) if page_id == 0 => write_ctrl(table, &data, range.clone())?,
+                _ => write_page(table, page_id, &data)?,
+            }
+        }
+        table.sync_all()?;
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.index.clear();
+        self.len = 0;
+        Ok(())
+    }
+}